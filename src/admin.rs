@@ -0,0 +1,228 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    blocklist, dbman,
+    reports::{self, Report},
+    utils, AppState,
+};
+
+fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        "This needs an admin token in an Authorization: Bearer header",
+    )
+        .into_response()
+}
+
+fn invalid_hash() -> Response {
+    (StatusCode::BAD_REQUEST, "Not a hex encoded sha256 hash").into_response()
+}
+
+// GET /api/admin/blocklist
+async fn list_blocklist(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if !utils::is_admin(&headers, &state.config) {
+        return unauthorized();
+    }
+    let entries = blocklist::list(&state.db).expect("couldn't read blocklist");
+    Json(entries).into_response()
+}
+
+// POST /api/admin/blocklist, the body is a hash list like the output of sha256sum
+async fn import_blocklist(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if !utils::is_admin(&headers, &state.config) {
+        return unauthorized();
+    }
+    let hash_list = String::from_utf8_lossy(&body);
+    let (added, invalid) = blocklist::import(&hash_list, Some("imported".to_string()), &state.db)
+        .expect("couldn't import blocklist");
+    log::info!("Imported {} hashes into the blocklist", added);
+    Json(json!({ "added": added, "invalid": invalid })).into_response()
+}
+
+#[derive(Deserialize, Default)]
+struct BlockBody {
+    reason: Option<String>,
+}
+
+// PUT /api/admin/blocklist/:hash, with an optional json body like {"reason": "..."}
+async fn block_hash(
+    Path(hash): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if !utils::is_admin(&headers, &state.config) {
+        return unauthorized();
+    }
+    let Some(hash) = blocklist::normalize_hash(&hash) else {
+        return invalid_hash();
+    };
+    let body: BlockBody = if body.is_empty() {
+        BlockBody::default()
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(x) => x,
+            Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        }
+    };
+    blocklist::add(&hash, body.reason, &state.db).expect("couldn't write blocklist");
+    log::info!("Added {} to the blocklist", hash);
+    StatusCode::NO_CONTENT.into_response()
+}
+
+// DELETE /api/admin/blocklist/:hash
+async fn unblock_hash(
+    Path(hash): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Response {
+    if !utils::is_admin(&headers, &state.config) {
+        return unauthorized();
+    }
+    let Some(hash) = blocklist::normalize_hash(&hash) else {
+        return invalid_hash();
+    };
+    if !blocklist::remove(&hash, &state.db).expect("couldn't write blocklist") {
+        return (StatusCode::NOT_FOUND, "This hash isn't blocked").into_response();
+    }
+    log::info!("Removed {} from the blocklist", hash);
+    StatusCode::NO_CONTENT.into_response()
+}
+
+// GET /api/admin/reports, open reports grouped by file
+async fn list_reports(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if !utils::is_admin(&headers, &state.config) {
+        return unauthorized();
+    }
+    let mut by_file: BTreeMap<String, Vec<Report>> = BTreeMap::new();
+    for report in reports::all(&state.db).expect("couldn't read reports") {
+        by_file
+            .entry(report.file_id.clone())
+            .or_default()
+            .push(report);
+    }
+    let queue: Vec<_> = by_file
+        .into_iter()
+        .map(|(id, reports)| {
+            // None if the file is gone already, its reports can still be dismissed
+            let file = dbman::read_file_info(id.clone(), &state.db).map(|x| {
+                json!({
+                    "name": x.name,
+                    "mime_type": x.detected_mime_type,
+                    "size": x.size,
+                    "hash": x.hash,
+                    "upload_date": x.upload_date,
+                    "hidden": x.hidden,
+                    "scan_result": x.scan_result,
+                })
+            });
+            json!({ "id": id, "file": file, "reports": reports })
+        })
+        .collect();
+    Json(queue).into_response()
+}
+
+fn not_found() -> Response {
+    (StatusCode::NOT_FOUND, "There's no file with this id").into_response()
+}
+
+// POST /api/admin/reports/:file/dismiss, keeps the file and shows it again if it was hidden
+async fn dismiss_reports(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Response {
+    if !utils::is_admin(&headers, &state.config) {
+        return unauthorized();
+    }
+    let dismissed = reports::remove_for_file(&id, &state.db).expect("couldn't remove reports");
+    let lock = dbman::FileLock::lock(&id).await;
+    if let Some(mut info) = dbman::read_file_info(id.clone(), &state.db) {
+        if info.hidden {
+            info.hidden = false;
+            dbman::write_file_info(&info, &lock, &state.db).expect("couldn't unhide file");
+        }
+    }
+    log::info!("Dismissed {} reports about {}", dismissed, id);
+    Json(json!({ "dismissed": dismissed })).into_response()
+}
+
+// POST /api/admin/reports/:file/delete, deletes the file along with its reports
+async fn delete_reported(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Response {
+    if !utils::is_admin(&headers, &state.config) {
+        return unauthorized();
+    }
+    let Some(info) = dbman::read_file_info(id.clone(), &state.db) else {
+        return not_found();
+    };
+    dbman::remove_file(&info, &state)
+        .await
+        .expect("couldn't delete file");
+    log::info!("Deleted reported file {}", id);
+    StatusCode::NO_CONTENT.into_response()
+}
+
+// POST /api/admin/reports/:file/blocklist, deletes the file and blocks its hash
+async fn blocklist_reported(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Response {
+    if !utils::is_admin(&headers, &state.config) {
+        return unauthorized();
+    }
+    let Some(info) = dbman::read_file_info(id.clone(), &state.db) else {
+        return not_found();
+    };
+    // the old revisions could be just as bad
+    let hashes: BTreeSet<String> = (0..=info.revision)
+        .filter_map(|x| dbman::read_revision_info(id.clone(), x, &state.db))
+        .map(|x| x.hash)
+        .filter(|x| !x.is_empty())
+        .collect();
+    for hash in &hashes {
+        blocklist::add(hash, Some(format!("reported file {}", id)), &state.db)
+            .expect("couldn't write blocklist");
+    }
+    dbman::remove_file(&info, &state)
+        .await
+        .expect("couldn't delete file");
+    log::info!(
+        "Deleted reported file {} and blocked {} hashes",
+        id,
+        hashes.len()
+    );
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Routes for instance admins, they all need one of the `admin_tokens`
+pub fn get_admin_router() -> Router<AppState> {
+    Router::new()
+        .route("/blocklist", get(list_blocklist))
+        .route("/blocklist", post(import_blocklist))
+        .route("/blocklist/:hash", put(block_hash))
+        .route("/blocklist/:hash", delete(unblock_hash))
+        .route("/reports", get(list_reports))
+        .route("/reports/:file/dismiss", post(dismiss_reports))
+        .route("/reports/:file/delete", post(delete_reported))
+        .route("/reports/:file/blocklist", post(blocklist_reported))
+}
@@ -1,93 +1,283 @@
 use std::collections::HashMap;
 
 use crate::{
-    dbman::{self, FileInfo},
-    utils::{should_preview, timebased_ratelimit, unique_id},
-    AppConfig, AppState,
+    admin, blocklist,
+    client_ip::ClientIp,
+    dbman::{self, BlobReader, FileInfo},
+    image_metadata,
+    password::{self, FileAccess},
+    ratelimit::{self, timebased_ratelimit, ThrottledReader},
+    reports,
+    scanner::ScanResult,
+    security, sniff, thumbnail, upload_rules,
+    utils::{self, content_hash, hash_deletion_key, paste_mime_type, should_preview},
+    AppConfig, AppState, ScanFailureAction,
 };
 use axum::{
-    body::{boxed, Bytes},
-    extract::{DefaultBodyLimit, Multipart, Path, Query, State},
+    body::{boxed, Bytes, Full},
+    extract::{DefaultBodyLimit, Form, Multipart, Path, Query, State},
     http::{
         header::{self},
-        HeaderMap, StatusCode,
+        HeaderMap, HeaderValue, StatusCode,
     },
-    response::{IntoResponse, Response},
-    routing::{delete, get, post},
+    response::{IntoResponse, Redirect, Response},
+    routing::{delete, get, patch, post, put},
     Router,
 };
-use axum_client_ip::ClientIp;
 use axum_extra::body::AsyncReadBody;
-use sha3::{Digest, Sha3_512};
-use tokio::{
-    fs::File,
-    io::{BufReader, BufStream},
-};
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use serde_json::json;
+use tokio::io::BufReader;
 use uuid::Uuid;
 
 // since multipart consumes body, it needs to be last for some reason. introduced in axum 0.6
 async fn upload(
     State(state): State<AppState>,
     ClientIp(ip_address): ClientIp,
+    headers: HeaderMap,
     mut multipart: Multipart,
 ) -> Response {
     struct FileFieldData {
         file_name: String,
         content_type: String,
-        bytes: Bytes,
+        bytes: Vec<u8>,
     }
 
     let mut maybe_file_field: Option<FileFieldData> = None;
-    while let Some(field) = multipart.next_field().await.unwrap() {
+    let mut slug: Option<String> = None;
+    let mut strip_metadata: Option<String> = None;
+    while let Some(mut field) = multipart.next_field().await.unwrap() {
+        if field.name() == Some("slug") {
+            slug = Some(field.text().await.expect("Couldn't read slug"));
+            continue;
+        }
+        if field.name() == Some("strip_metadata") {
+            strip_metadata = Some(field.text().await.expect("Couldn't read strip_metadata"));
+            continue;
+        }
         if field.name() != Some("file") {
             continue;
         }
+        let file_name = field
+            .file_name()
+            .expect("couldn't read file name")
+            .to_string();
+        let content_type = field
+            .content_type()
+            .expect("couldn't read content type")
+            .to_string();
+
+        // checked before reading the body, the detected type is checked again later
+        let type_check =
+            upload_rules::check_extension(&utils::sanitize_file_name(&file_name), &state.config)
+                .and_then(|_| upload_rules::check_mime_type(&content_type, &state.upload_rules));
+        if let Err(e) = type_check {
+            return e.into_response();
+        }
+
+        let mut bytes = Vec::new();
+        while let Some(chunk) = field.chunk().await.expect("Couldn't read bytes of file") {
+            bytes.extend_from_slice(&chunk);
+            if let Err(e) =
+                upload_rules::check_size(bytes.len(), &content_type, &state.upload_rules)
+            {
+                return e.into_response();
+            }
+        }
         maybe_file_field = Some(FileFieldData {
-            file_name: field
-                .file_name()
-                .expect("couldn't read file name")
-                .to_string(),
-            content_type: field
-                .content_type()
-                .expect("couldn't read content type")
-                .to_string(),
-            bytes: field.bytes().await.expect("Couldn't read bytes of file"),
+            file_name,
+            content_type,
+            bytes,
         })
     }
 
     let file_field = maybe_file_field.expect("Couldn't read file from multipart");
+    let strip_metadata =
+        match image_metadata::should_strip(strip_metadata.as_deref(), &state.config) {
+            Ok(x) => x,
+            Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+        };
 
-    let uid = unique_id();
+    store_upload(
+        &state,
+        &ratelimit::ip_ratelimit_token(ip_address, &state.config),
+        &headers,
+        UploadOptions {
+            slug,
+            strip_metadata,
+        },
+        file_field.file_name,
+        file_field.content_type,
+        file_field.bytes,
+    )
+    .await
+}
 
-    let actual_deletion_key = Uuid::new_v4().to_string();
+#[derive(Deserialize)]
+struct PasteForm {
+    content: String,
+    /// Optional file name, the extension decides the language if none is given
+    name: Option<String>,
+    /// Optional file extension from `utils::PASTE_LANGUAGES`
+    language: Option<String>,
+    /// Optional custom id, only for uploaders with a token
+    slug: Option<String>,
+}
 
-    let mut hasher = Sha3_512::new();
+async fn paste(
+    State(state): State<AppState>,
+    ClientIp(ip_address): ClientIp,
+    headers: HeaderMap,
+    Form(form): Form<PasteForm>,
+) -> Response {
+    if form.content.len() as u128 > state.config.paste_size_limit.get_bytes() {
+        return (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "Pastes can't be larger than {}",
+                state.config.paste_size_limit.get_appropriate_unit(true)
+            ),
+        )
+            .into_response();
+    }
 
-    hasher.update(&actual_deletion_key);
-    hasher.update(&file_field.file_name); // salt, idk if needed but why not
+    let language = form.language.filter(|x| !x.is_empty());
+    let name = form.name.filter(|x| !x.is_empty()).unwrap_or_else(|| {
+        format!(
+            "paste.{}",
+            language.clone().unwrap_or_else(|| "txt".to_string())
+        )
+    });
+    let mime_type = paste_mime_type(&name, language.as_deref());
+
+    store_upload(
+        &state,
+        &ratelimit::ip_ratelimit_token(ip_address, &state.config),
+        &headers,
+        UploadOptions {
+            slug: form.slug,
+            strip_metadata: false,
+        },
+        name,
+        mime_type.to_string(),
+        form.content.into_bytes(),
+    )
+    .await
+}
 
-    let hashed_deletion_key_raw = hasher.finalize();
+/// Scans an upload if there's a scanner. Depending on `scan_failure_action`, uploads that
+/// aren't clean are either rejected with an error response or stored in quarantine.
+async fn scan_upload(bytes: &[u8], state: &AppState) -> Result<ScanResult, Response> {
+    let Some(scanner) = &state.scanner else {
+        return Ok(ScanResult::NotScanned);
+    };
+    let scan_result = scanner.scan(bytes).await.unwrap_or_else(|e| {
+        log::error!("Couldn't scan upload: {}", e);
+        ScanResult::Failed(e.to_string())
+    });
+    if scan_result.is_clean() || state.config.scan_failure_action == ScanFailureAction::Quarantine {
+        return Ok(scan_result);
+    }
+    Err(match scan_result {
+        ScanResult::Infected(name) => {
+            log::warn!("Rejected upload infected with {}", name);
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("This file looks like malware ({})", name),
+            )
+                .into_response()
+        }
+        _ => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Couldn't scan this file for malware, try again later",
+        )
+            .into_response(),
+    })
+}
 
-    let hashed_deletion_key =
-        base64::encode_config(hashed_deletion_key_raw, base64::URL_SAFE).replace('=', "");
+fn blocked_content() -> Response {
+    (
+        StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS,
+        "This file was taken down before and can't be uploaded again",
+    )
+        .into_response()
+}
 
-    let file_info = FileInfo {
-        mime_type: file_field.content_type,
-        upload_date: chrono::offset::Utc::now(),
-        deletion_key: hashed_deletion_key,
-        id: uid.clone(),
-        name: file_field.file_name,
-        size: file_field.bytes.len(),
+/// What the uploader asked for besides the file itself
+struct UploadOptions {
+    /// Custom id, only for uploaders with a token
+    slug: Option<String>,
+    strip_metadata: bool,
+}
+
+/// Ratelimits, stores and responds with the info of a newly uploaded file,
+/// including its plaintext deletion key.
+async fn store_upload(
+    state: &AppState,
+    ratelimit_token: &str,
+    headers: &HeaderMap,
+    options: UploadOptions,
+    file_name: String,
+    content_type: String,
+    bytes: Vec<u8>,
+) -> Response {
+    let file_name = utils::sanitize_file_name(&file_name);
+    let detected_mime_type = sniff::detect(&bytes);
+    if let Err(e) = upload_rules::check(
+        &file_name,
+        &content_type,
+        &detected_mime_type,
+        bytes.len(),
+        &state.config,
+        &state.upload_rules,
+    ) {
+        return e.into_response();
+    }
+
+    // the original is checked too, in case it was blocked before it got stripped
+    let original_hash = content_hash(&bytes);
+    let bytes = if options.strip_metadata {
+        match strip_image(bytes, &detected_mime_type).await {
+            Ok(x) => x,
+            Err(response) => return response,
+        }
+    } else {
+        bytes
     };
+    let hash = content_hash(&bytes);
+    for hash in [&original_hash, &hash] {
+        if blocklist::is_blocked(hash, &state.db).expect("couldn't read blocklist") {
+            log::warn!("Refused blocked upload {} from {}", hash, ratelimit_token);
+            return blocked_content();
+        }
+    }
+
+    let slug = options.slug.filter(|x| !x.is_empty());
+    if let Some(slug) = &slug {
+        if !utils::is_upload_token_valid(headers, &state.config) {
+            return (
+                StatusCode::UNAUTHORIZED,
+                "Custom slugs need an upload token in an Authorization: Bearer header",
+            )
+                .into_response();
+        }
+        if let Err(e) = utils::validate_slug(slug, &state.slug_regex) {
+            return (StatusCode::BAD_REQUEST, e).into_response();
+        }
+        // checked again when reserving it, this is just to fail before using any quota
+        if state
+            .db
+            .contains_key(format!("metadata:{}", slug))
+            .unwrap_or(true)
+        {
+            return (StatusCode::CONFLICT, "This slug is already taken").into_response();
+        }
+    }
 
     // TODO: should we really use ip for ratelimiting?
-    let ratelimited = !timebased_ratelimit(
-        &ip_address.to_string(),
-        file_info.size as u64,
-        &state,
-        false,
-    )
-    .expect("couldn't check ratelimiter");
+    let ratelimited = !timebased_ratelimit(ratelimit_token, bytes.len() as u64, state, false)
+        .expect("couldn't check ratelimiter");
 
     // TODO: check ratelimiter before entire body is received
     if ratelimited {
@@ -98,7 +288,58 @@ async fn upload(
             .into_response();
     }
 
-    dbman::store_file(file_field.bytes.try_into().unwrap(), &file_info, &state)
+    let scan_result = match scan_upload(&bytes, state).await {
+        Ok(x) => x,
+        Err(response) => return response,
+    };
+
+    let uid = match slug {
+        Some(slug) => {
+            if !dbman::reserve_id(&slug, &state.db).expect("couldn't reserve slug") {
+                return (StatusCode::CONFLICT, "This slug is already taken").into_response();
+            }
+            slug
+        }
+        None => match dbman::reserve_new_id(&state.config, &state.db) {
+            Ok(id) => id,
+            Err(e) => {
+                log::error!("Couldn't reserve an id: {}", e);
+                return (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Couldn't find a free id, try again",
+                )
+                    .into_response();
+            }
+        },
+    };
+
+    let actual_deletion_key = Uuid::new_v4().to_string();
+
+    let hashed_deletion_key = hash_deletion_key(&actual_deletion_key, &file_name);
+
+    let file_info = FileInfo {
+        mime_type: content_type,
+        detected_mime_type,
+        upload_date: chrono::offset::Utc::now(),
+        deletion_key: hashed_deletion_key,
+        id: uid,
+        name: file_name,
+        size: bytes.len(),
+        hash,
+        expiry_date: state
+            .config
+            .file_expiry_length
+            // an expiry length that large might as well be forever
+            .map(|x| utils::expiry_date_in(x).unwrap_or(chrono::DateTime::<chrono::Utc>::MAX_UTC)),
+        description: None,
+        preview: true,
+        password_hash: None,
+        revision: 0,
+        scan_result,
+        hidden: false,
+    };
+
+    dbman::store_file(bytes, &file_info, state)
         .await
         .expect("failed to store file");
 
@@ -114,16 +355,37 @@ async fn upload(
     ))
 }
 
+type DecodedReader = BufReader<async_compression::tokio::bufread::BrotliDecoder<BlobReader>>;
+
 enum Either<L, R> {
     Left(L),
     Right(R),
 }
 
+#[derive(Deserialize)]
+struct DownloadQuery {
+    /// Revision to download, the current one if not set
+    rev: Option<u32>,
+}
+
 async fn download(
     Path(uid): Path<String>,
+    Query(query): Query<DownloadQuery>,
     State(state): State<AppState>,
+    ClientIp(ip_address): ClientIp,
     headers: HeaderMap,
 ) -> Response /*<tokio::io::BufReader<tokio::fs::File>>*/ {
+    let ratelimit_token = ratelimit::ip_ratelimit_token(ip_address, &state.config);
+    let retry_after = ratelimit::download_request_ratelimit(&ratelimit_token, &state)
+        .expect("couldn't check ratelimiter");
+    if let Some(retry_after) = retry_after {
+        return Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header(header::RETRY_AFTER, retry_after.as_secs().max(1))
+            .body(boxed("Too many downloads, slow down".to_string()))
+            .unwrap();
+    }
+
     let accept_encoding = headers.get("Accept-Encoding");
     let use_brotli = match accept_encoding {
         None => false,
@@ -132,62 +394,111 @@ async fn download(
             Ok(x) => x.contains("br"),
         },
     };
-    let maybe_file = dbman::read_file(uid.clone(), &state).await;
-    if maybe_file.is_none() {
+
+    let maybe_info = dbman::read_file_info(uid.clone(), &state.db);
+    if maybe_info.is_none() {
         return Response::builder()
             .status(404)
             .body(boxed("404".to_string())) // I have no idea why this needs to be boxed but whatever
             .unwrap();
     }
-    let (file_buf_reader, brotli_length) = maybe_file.unwrap();
-    let maybe_file: Option<
-        Either<
-            AsyncReadBody<BufReader<File>>,
-            AsyncReadBody<
-                tokio::io::BufReader<
-                    async_compression::tokio::bufread::BrotliDecoder<
-                        BufStream<tokio::io::BufReader<tokio::fs::File>>,
-                    >,
-                >,
-            >,
-        >,
-    >;
-
-    if !use_brotli {
-        maybe_file = Some(Either::Right(AsyncReadBody::new(
-            dbman::decode(file_buf_reader)
-                .await
-                .expect("couldn't decode brotli stream"),
-        )));
-    } else {
-        maybe_file = Some(Either::Left(AsyncReadBody::new(file_buf_reader)));
+    let current_info = maybe_info.unwrap();
+
+    // old revisions are protected by the current password, and hidden along with the file
+    match password::file_access(&current_info, &headers, &ratelimit_token, &state).await {
+        FileAccess::Allowed => {}
+        FileAccess::Denied => return password_required(),
+        FileAccess::Ratelimited(retry_after) => return password::too_many_attempts(retry_after),
+    }
+    if current_info.is_withheld() {
+        return withheld(&current_info);
     }
 
-    let file = maybe_file.unwrap();
+    // previews belong on the user content domain, where they can't touch filebin's cookies
+    if should_preview(&current_info, &state.config)
+        && current_info.password_hash.is_none()
+        && !utils::is_user_content_request(&state.config, &headers)
+    {
+        if let Some(user_content_url) = &state.config.user_content_url {
+            let revision = query.rev.map(|x| format!("?rev={}", x)).unwrap_or_default();
+            return Redirect::temporary(&format!(
+                "{}{}{}",
+                user_content_url.trim_end_matches('/'),
+                utils::get_download_link(uid),
+                revision
+            ))
+            .into_response();
+        }
+    }
 
-    let maybe_info = dbman::read_file_info(uid, &state.db);
+    let maybe_info = match query.rev {
+        Some(revision) => dbman::read_revision_info(uid, revision, &state.db),
+        None => Some(current_info),
+    };
     if maybe_info.is_none() {
         return Response::builder()
             .status(404)
-            .body(boxed("404".to_string())) // I have no idea why this needs to be boxed but whatever
+            .body(boxed("No such revision".to_string()))
             .unwrap();
     }
     let info = maybe_info.unwrap();
+    if info.is_withheld() {
+        return withheld(&info);
+    }
 
-    let should_preview = should_preview(&info.mime_type, &state.config);
+    let maybe_file = dbman::read_file(&info, &state).await;
+    if maybe_file.is_none() {
+        return Response::builder()
+            .status(404)
+            .body(boxed("404".to_string())) // I have no idea why this needs to be boxed but whatever
+            .unwrap();
+    }
+    let (file_buf_reader, brotli_length) = maybe_file.unwrap();
+    let file: Either<
+        AsyncReadBody<ThrottledReader<BlobReader>>,
+        AsyncReadBody<ThrottledReader<DecodedReader>>,
+    > = if !use_brotli {
+        Either::Right(AsyncReadBody::new(ThrottledReader::new(
+            dbman::decode(file_buf_reader)
+                .await
+                .expect("couldn't decode brotli stream"),
+            info.size as u64,
+            ratelimit_token,
+            state.clone(),
+        )))
+    } else {
+        Either::Left(AsyncReadBody::new(ThrottledReader::new(
+            file_buf_reader,
+            brotli_length,
+            ratelimit_token,
+            state.clone(),
+        )))
+    };
 
+    let should_preview = should_preview(&info, &state.config);
+
+    // the claimed type is only trusted for downloads, where it's harmless
+    let content_type = if should_preview {
+        &info.detected_mime_type
+    } else {
+        &info.mime_type
+    };
     let mut builder = Response::builder()
-        .header(header::CONTENT_TYPE, info.mime_type)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .header(
+            header::CONTENT_SECURITY_POLICY,
+            security::user_content_csp(&state.config),
+        )
         .header(
             header::CONTENT_DISPOSITION,
-            format!(
-                "{}; filename=\"{}\"",
+            utils::content_disposition(
                 if should_preview {
                     "inline"
                 } else {
                     "attachment"
                 },
-                info.name // TODO: Filter so it won't be able to escape the ""s if that matters?
+                &info.name,
             ),
         );
     if use_brotli {
@@ -205,36 +516,343 @@ async fn download(
             ""
         }
     );
+    if let Err(e) = dbman::increment_download_count(&info.id, &state.db) {
+        log::error!("Couldn't count download of {}: {}", info.id, e);
+    }
     match file {
         Either::Left(x) => builder.body(x).unwrap().into_response(),
         Either::Right(x) => builder.body(x).unwrap().into_response(),
     }
 }
 
+// PUT /api/file/:file/content, the body is the new contents and the deletion key
+// has to be in a header. The Content-Type header changes the mime type if it's set.
+#[derive(Deserialize)]
+struct ReplaceQuery {
+    /// Overrides the strip_image_metadata config
+    strip_metadata: Option<String>,
+}
+
+async fn replace(
+    Path(uid): Path<String>,
+    Query(query): Query<ReplaceQuery>,
+    State(state): State<AppState>,
+    ClientIp(ip_address): ClientIp,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let maybe_key = find_deletion_key(&headers, &HashMap::new(), &[]);
+    if maybe_key.is_none() {
+        return Response::builder()
+            .status(400)
+            .body(boxed(
+                "You need to provide a deletion key in a X-Deletion-Key header".to_string(),
+            ))
+            .unwrap();
+    }
+
+    let maybe_info = dbman::read_file_info(uid.clone(), &state.db);
+    if maybe_info.is_none() {
+        return Response::builder()
+            .status(404)
+            .body(boxed("404".to_string())) // I have no idea why this needs to be boxed but whatever
+            .unwrap();
+    }
+    let info = maybe_info.unwrap();
+
+    let key = maybe_key.unwrap();
+    if !info.is_valid_deletion_key(&key) {
+        return Response::builder()
+            .status(400)
+            .body(boxed("Invalid deletion key".to_string()))
+            .unwrap();
+    }
+
+    if body.len() as u128 > state.config.file_size_limit.get_bytes() {
+        return (StatusCode::PAYLOAD_TOO_LARGE, "File is too large").into_response();
+    }
+
+    let mime_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .filter(|x| x.parse::<mime::Mime>().is_ok())
+        .map(str::to_string);
+    let strip_metadata =
+        match image_metadata::should_strip(query.strip_metadata.as_deref(), &state.config) {
+            Ok(x) => x,
+            Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+        };
+
+    let detected_mime_type = sniff::detect(&body);
+    if let Err(e) = upload_rules::check(
+        &info.name,
+        mime_type.as_deref().unwrap_or(&info.mime_type),
+        &detected_mime_type,
+        body.len(),
+        &state.config,
+        &state.upload_rules,
+    ) {
+        return e.into_response();
+    }
+
+    let ratelimit_token = ratelimit::ip_ratelimit_token(ip_address, &state.config);
+    let original_hash = content_hash(&body);
+    let body = if strip_metadata {
+        match strip_image(body.into(), &detected_mime_type).await {
+            Ok(x) => x,
+            Err(response) => return response,
+        }
+    } else {
+        body.into()
+    };
+    let hash = content_hash(&body);
+    for hash in [&original_hash, &hash] {
+        if blocklist::is_blocked(hash, &state.db).expect("couldn't read blocklist") {
+            log::warn!(
+                "Refused blocked replacement {} from {}",
+                hash,
+                ratelimit_token
+            );
+            return blocked_content();
+        }
+    }
+
+    let ratelimited = !timebased_ratelimit(&ratelimit_token, body.len() as u64, &state, false)
+        .expect("couldn't check ratelimiter");
+    if ratelimited {
+        return (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Uploading this file will override your upload limit of today. Return in 24 hours.",
+        )
+            .into_response();
+    }
+
+    let scan_result = match scan_upload(&body, &state).await {
+        Ok(x) => x,
+        Err(response) => return response,
+    };
+
+    let new_info = dbman::replace_file(body, uid, mime_type, scan_result, &state)
+        .await
+        .expect("failed to replace file");
+
+    log::info!(
+        "Replaced contents of {} with revision {}",
+        &new_info.id,
+        new_info.revision
+    );
+
+    let exposed_file_info = FileInfo {
+        deletion_key: key, // not recoverable, hashed
+        ..new_info
+    };
+
+    IntoResponse::into_response(boxed(
+        serde_json::to_string(&exposed_file_info).expect("failed to convert file data to json"),
+    ))
+}
+
+/// Removes the metadata of an image, other files are returned as they are
+async fn strip_image(bytes: Vec<u8>, mime_type: &str) -> Result<Vec<u8>, Response> {
+    if !image_metadata::is_strippable(mime_type) {
+        return Ok(bytes);
+    }
+    let mime_type = mime_type.to_string();
+    let stripped = tokio::task::spawn_blocking(move || {
+        image_metadata::strip(&bytes, &mime_type).map(|x| x.unwrap_or(bytes))
+    })
+    .await
+    .unwrap_or_else(|e| Err(e.into()));
+    stripped.map_err(|e| {
+        log::warn!("Couldn't strip image metadata: {}", e);
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Couldn't remove the metadata of this image, upload it with strip_metadata=false to keep it",
+        )
+            .into_response()
+    })
+}
+
+/// Response for files that are kept around but can't be seen, see `FileInfo::is_withheld`
+pub fn withheld(info: &FileInfo) -> Response {
+    let reason = if info.is_quarantined() {
+        "This file is quarantined, it didn't pass the malware scan"
+    } else {
+        "This file was reported and is hidden until a moderator looks at it"
+    };
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(boxed(reason.to_string()))
+        .unwrap()
+}
+
+fn password_required() -> Response {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .body(boxed(
+            "This file is password protected, send the password in a X-File-Password header"
+                .to_string(),
+        ))
+        .unwrap()
+}
+
+async fn thumbnail(
+    Path(uid): Path<String>,
+    State(state): State<AppState>,
+    ClientIp(ip_address): ClientIp,
+    headers: HeaderMap,
+) -> Response {
+    let ratelimit_token = ratelimit::ip_ratelimit_token(ip_address, &state.config);
+    let retry_after = ratelimit::download_request_ratelimit(&ratelimit_token, &state)
+        .expect("couldn't check ratelimiter");
+    if let Some(retry_after) = retry_after {
+        return Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header(header::RETRY_AFTER, retry_after.as_secs().max(1))
+            .body(boxed("Too many downloads, slow down".to_string()))
+            .unwrap();
+    }
+
+    let maybe_info = dbman::read_file_info(uid.clone(), &state.db);
+    if maybe_info.is_none() {
+        return Response::builder()
+            .status(404)
+            .body(boxed("404".to_string())) // I have no idea why this needs to be boxed but whatever
+            .unwrap();
+    }
+    let info = maybe_info.unwrap();
+
+    match password::file_access(&info, &headers, &ratelimit_token, &state).await {
+        FileAccess::Allowed => {}
+        FileAccess::Denied => return password_required(),
+        FileAccess::Ratelimited(retry_after) => return password::too_many_attempts(retry_after),
+    }
+    if info.is_withheld() {
+        return withheld(&info);
+    }
+
+    if !thumbnail::has_thumbnail(&info, &state.config) {
+        return Response::builder()
+            .status(404)
+            .body(boxed("This file doesn't have a thumbnail".to_string()))
+            .unwrap();
+    }
+
+    let thumbnail = match dbman::read_thumbnail(&uid, &state).await {
+        Some(x) if x.is_empty() => {
+            return Response::builder()
+                .status(404)
+                .body(boxed("Couldn't generate a thumbnail".to_string()))
+                .unwrap();
+        }
+        Some(x) => x,
+        None => {
+            let bytes = dbman::read_file_bytes(&info, &state)
+                .await
+                .expect("couldn't read image");
+            let size = state.config.thumbnail_size;
+            let generated = tokio::task::spawn_blocking(move || thumbnail::generate(&bytes, size))
+                .await
+                .expect("thumbnail task panicked");
+            match generated {
+                Ok(x) => {
+                    if let Err(e) = dbman::write_thumbnail(&uid, &x, &state).await {
+                        log::error!("Couldn't write thumbnail of {}: {}", uid, e);
+                    }
+                    x
+                }
+                Err(e) => {
+                    log::warn!("Couldn't generate thumbnail of {}: {}", uid, e);
+                    // remembered so broken images aren't decoded again on every request
+                    if let Err(e) = dbman::write_thumbnail(&uid, &[], &state).await {
+                        log::error!("Couldn't write thumbnail of {}: {}", uid, e);
+                    }
+                    return Response::builder()
+                        .status(404)
+                        .body(boxed("Couldn't generate a thumbnail".to_string()))
+                        .unwrap();
+                }
+            }
+        }
+    };
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, thumbnail::THUMBNAIL_MIME_TYPE)
+        .header(
+            header::CACHE_CONTROL,
+            if info.password_hash.is_some() {
+                "private, max-age=86400"
+            } else {
+                "public, max-age=86400"
+            },
+        )
+        .body(boxed(Full::from(thumbnail)))
+        .unwrap()
+}
+
+#[derive(Deserialize)]
+struct DeletionKeyBody {
+    key: String,
+}
+
+/// Finds the deletion key in the `X-Deletion-Key` or `Authorization: Bearer` header,
+/// a json or form body, or the `key` query parameter, in that order.
+/// Anything but the query parameter is preferred, since urls end up in access logs.
+fn find_deletion_key(
+    headers: &HeaderMap,
+    params: &HashMap<String, String>,
+    body: &[u8],
+) -> Option<String> {
+    let header_str = |name| {
+        headers
+            .get(name)
+            .and_then(|x: &HeaderValue| x.to_str().ok())
+    };
+
+    if let Some(key) = header_str("x-deletion-key") {
+        return Some(key.to_string());
+    }
+    if let Some(key) =
+        header_str(header::AUTHORIZATION.as_str()).and_then(|x| x.strip_prefix("Bearer "))
+    {
+        return Some(key.to_string());
+    }
+
+    let content_type = header_str(header::CONTENT_TYPE.as_str()).unwrap_or_default();
+    let from_body: Option<DeletionKeyBody> = if content_type.starts_with("application/json") {
+        serde_json::from_slice(body).ok()
+    } else if content_type.starts_with("application/x-www-form-urlencoded") {
+        serde_urlencoded::from_bytes(body).ok()
+    } else {
+        None
+    };
+    if let Some(x) = from_body {
+        return Some(x.key);
+    }
+
+    params.get("key").cloned()
+}
+
+// DELETE /api/file/:file and POST /api/file/:file/delete, the latter is for html forms
 async fn erase(
     Path(uid): Path<String>,
     Query(params): Query<HashMap<String, String>>,
     State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
 ) -> Response {
-    if params.get("key").is_none() {
+    let maybe_key = find_deletion_key(&headers, &params, &body);
+    if maybe_key.is_none() {
         return Response::builder()
             .status(400)
             .body(boxed(
-                "You need to provide a deletion key. DELETE /api/file/[ID]?key=[DELETION_KEY]"
+                "You need to provide a deletion key. DELETE /api/file/[ID] with a X-Deletion-Key: [DELETION_KEY] header, \
+                or POST /api/file/[ID]/delete with key=[DELETION_KEY] in the body"
                     .to_string(),
             )) // I have no idea why this needs to be boxed but whatever
             .unwrap();
     }
-    let maybe_deleted = dbman::delete_file(
-        uid,
-        params
-            .get("key")
-            .ok_or("Couldn't find deletion key (should'ave been checked for above)")
-            .unwrap()
-            .to_owned(),
-        &state,
-    )
-    .await;
+    let maybe_deleted = dbman::delete_file(uid, maybe_key.unwrap(), &state).await;
     if maybe_deleted.is_err() {
         return Response::builder()
             .status(500)
@@ -253,6 +871,320 @@ async fn erase(
     IntoResponse::into_response("Deletion successful")
 }
 
+#[derive(Deserialize)]
+struct ReportForm {
+    reason: String,
+    /// Optional email or similar, for moderators to get back to the reporter
+    contact: Option<String>,
+}
+
+const REPORT_REASON_LIMIT: usize = 2000;
+const REPORT_CONTACT_LIMIT: usize = 200;
+
+// POST /api/file/:file/report, a form with a reason and an optional contact
+async fn report(
+    Path(uid): Path<String>,
+    State(state): State<AppState>,
+    ClientIp(ip_address): ClientIp,
+    Form(form): Form<ReportForm>,
+) -> Response {
+    let reason = form.reason.trim().to_string();
+    let contact = form
+        .contact
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty());
+    if reason.is_empty() {
+        return (StatusCode::BAD_REQUEST, "The report needs a reason").into_response();
+    }
+    if reason.chars().count() > REPORT_REASON_LIMIT
+        || contact
+            .as_ref()
+            .is_some_and(|x| x.chars().count() > REPORT_CONTACT_LIMIT)
+    {
+        return (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "Reasons can be {} characters long, contacts {}",
+                REPORT_REASON_LIMIT, REPORT_CONTACT_LIMIT
+            ),
+        )
+            .into_response();
+    }
+
+    if dbman::read_file_info(uid.clone(), &state.db).is_none() {
+        return Response::builder()
+            .status(404)
+            .body(boxed("404".to_string())) // I have no idea why this needs to be boxed but whatever
+            .unwrap();
+    }
+
+    let ratelimit_token = ratelimit::ip_ratelimit_token(ip_address, &state.config);
+    let retry_after =
+        ratelimit::report_ratelimit(&ratelimit_token, &state).expect("couldn't check ratelimiter");
+    if let Some(retry_after) = retry_after {
+        return Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header(header::RETRY_AFTER, retry_after.as_secs().max(1))
+            .body(boxed("Too many reports, slow down".to_string()))
+            .unwrap();
+    }
+
+    let reporters = reports::add(&uid, reason, contact, ratelimit_token, &state.db)
+        .expect("couldn't store report");
+    log::info!("File {} was reported, {} reporters so far", uid, reporters);
+
+    if state
+        .config
+        .report_hide_threshold
+        .is_some_and(|x| reporters >= x)
+    {
+        let lock = dbman::FileLock::lock(&uid).await;
+        if let Some(mut info) = dbman::read_file_info(uid.clone(), &state.db) {
+            if !info.hidden {
+                info.hidden = true;
+                dbman::write_file_info(&info, &lock, &state.db).expect("couldn't hide file");
+                log::warn!("Hid file {} after {} reports", uid, reporters);
+            }
+        }
+    }
+
+    (StatusCode::ACCEPTED, "Thanks, a moderator will look at it").into_response()
+}
+
+/// Lets fields that are `null` be told apart from missing ones
+fn double_option<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// Changes to a files metadata, missing fields are left as they are
+#[derive(Deserialize)]
+struct FileInfoPatch {
+    name: Option<String>,
+    mime_type: Option<String>,
+    preview: Option<bool>,
+    /// Seconds from now until the file expires, null makes it never expire
+    #[serde(default, deserialize_with = "double_option")]
+    expires_in: Option<Option<u64>>,
+    #[serde(default, deserialize_with = "double_option")]
+    description: Option<Option<String>>,
+    /// null removes the password
+    #[serde(default, deserialize_with = "double_option")]
+    password: Option<Option<String>>,
+}
+
+const DESCRIPTION_MAX_LENGTH: usize = 2000;
+
+// PATCH /api/file/:file, takes a FileInfoPatch as json and the deletion key like erase does
+async fn edit(
+    Path(uid): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let bad_request = |message: &str| {
+        Response::builder()
+            .status(400)
+            .body(boxed(message.to_string()))
+            .unwrap()
+    };
+
+    let maybe_key = find_deletion_key(&headers, &params, &body);
+    if maybe_key.is_none() {
+        return bad_request("You need to provide a deletion key in a X-Deletion-Key header");
+    }
+    let key = maybe_key.unwrap();
+
+    let patch: FileInfoPatch = match serde_json::from_slice(&body) {
+        Ok(x) => x,
+        Err(e) => return bad_request(&format!("Invalid json: {}", e)),
+    };
+
+    // held until the changes are written, so they don't undo a replacement or another edit
+    let lock = dbman::FileLock::lock(&uid).await;
+    let maybe_info = dbman::read_file_info(uid, &state.db);
+    if maybe_info.is_none() {
+        return Response::builder()
+            .status(404)
+            .body(boxed("404".to_string())) // I have no idea why this needs to be boxed but whatever
+            .unwrap();
+    }
+    let mut info = maybe_info.unwrap();
+
+    if !info.is_valid_deletion_key(&key) {
+        return bad_request("Invalid deletion key");
+    }
+
+    let highlighting_changed = patch.name.is_some() || patch.mime_type.is_some();
+    if let Some(name) = patch.name {
+        if name.trim().is_empty() {
+            return bad_request("The name can't be empty");
+        }
+        let name = utils::sanitize_file_name(&name);
+        if let Err(e) = upload_rules::check_extension(&name, &state.config) {
+            return e.into_response();
+        }
+        // the deletion key is salted with the name
+        info.deletion_key = hash_deletion_key(&key, &name);
+        info.name = name;
+    }
+    if let Some(mime_type) = patch.mime_type {
+        if mime_type.parse::<mime::Mime>().is_err() {
+            return bad_request("Invalid mime type");
+        }
+        if let Err(e) = upload_rules::check_mime_type(&mime_type, &state.upload_rules) {
+            return e.into_response();
+        }
+        info.mime_type = mime_type;
+    }
+    if let Some(preview) = patch.preview {
+        info.preview = preview;
+    }
+    if let Some(expires_in) = patch.expires_in {
+        let max_expiry_length = state.config.file_expiry_length;
+        info.expiry_date = match (expires_in, max_expiry_length) {
+            (None, Some(_)) => return bad_request("Files on this instance have to expire"),
+            (Some(x), Some(max)) if x > max => {
+                return bad_request(&format!("Files can't expire later than in {} seconds", max))
+            }
+            (None, None) => None,
+            (Some(x), _) => match utils::expiry_date_in(x) {
+                Some(date) => Some(date),
+                None => return bad_request("expires_in is too large"),
+            },
+        };
+    }
+    if let Some(description) = patch.description {
+        if description.as_ref().map_or(0, |x| x.chars().count()) > DESCRIPTION_MAX_LENGTH {
+            return bad_request(&format!(
+                "Descriptions can't be longer than {} characters",
+                DESCRIPTION_MAX_LENGTH
+            ));
+        }
+        info.description = description.filter(|x| !x.is_empty());
+    }
+    if let Some(password) = patch.password {
+        info.password_hash = match password.filter(|x| !x.is_empty()) {
+            Some(x) => Some(password::hash_password(x).await),
+            None => None,
+        };
+    }
+
+    dbman::write_file_info(&info, &lock, &state.db).expect("failed to write file info");
+    drop(lock);
+    if highlighting_changed {
+        if let Err(e) = dbman::remove_highlight_cache(&info.id, &state).await {
+            log::warn!("Couldn't remove highlight cache of {}: {}", info.id, e);
+        }
+    }
+
+    log::info!("Edited metadata of {}", &info.id);
+
+    let exposed_file_info = FileInfo {
+        deletion_key: key, // not recoverable, hashed
+        ..info
+    };
+
+    IntoResponse::into_response(boxed(
+        serde_json::to_string(&exposed_file_info).expect("failed to convert file data to json"),
+    ))
+}
+
+#[derive(Deserialize)]
+struct OEmbedQuery {
+    /// Link to a file or paste page
+    url: String,
+    format: Option<String>,
+}
+
+/// oEmbed provider, see https://oembed.com
+async fn oembed(
+    Query(query): Query<OEmbedQuery>,
+    State(state): State<AppState>,
+    ClientIp(ip_address): ClientIp,
+    headers: HeaderMap,
+) -> Response {
+    if query.format.as_deref().unwrap_or("json") != "json" {
+        return Response::builder()
+            .status(StatusCode::NOT_IMPLEMENTED)
+            .body(boxed("Only json is supported".to_string()))
+            .unwrap();
+    }
+
+    // it reads files, so it counts like a download
+    let ratelimit_token = ratelimit::ip_ratelimit_token(ip_address, &state.config);
+    let retry_after = ratelimit::download_request_ratelimit(&ratelimit_token, &state)
+        .expect("couldn't check ratelimiter");
+    if let Some(retry_after) = retry_after {
+        return Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header(header::RETRY_AFTER, retry_after.as_secs().max(1))
+            .body(boxed("Too many requests, slow down".to_string()))
+            .unwrap();
+    }
+
+    let base_url = utils::base_url(&state.config, &headers);
+    // only links to this instance, the scheme doesn't matter since proxies can change it
+    let without_scheme = |x: &str| x.split_once("://").map_or(x, |(_, rest)| rest).to_string();
+    let page_regex = Regex::new(r"^/(?:file|p)/([^/?#]+)").unwrap();
+    let maybe_info = without_scheme(&query.url)
+        .strip_prefix(&without_scheme(&base_url))
+        .and_then(|path| page_regex.captures(path))
+        .and_then(|x| dbman::read_file_info(x[1].to_string(), &state.db))
+        .filter(|x| !x.is_withheld());
+    if maybe_info.is_none() {
+        return Response::builder()
+            .status(404)
+            .body(boxed("404".to_string())) // I have no idea why this needs to be boxed but whatever
+            .unwrap();
+    }
+    let info = maybe_info.unwrap();
+
+    let mut response = json!({
+        "version": "1.0",
+        "type": "link",
+        "title": info.name,
+        "provider_name": "filebin",
+        "provider_url": base_url,
+    });
+
+    // protected files only get their title shown
+    if info.password_hash.is_none() && thumbnail::has_thumbnail(&info, &state.config) {
+        let maybe_dimensions =
+            dbman::read_file_start(&info, thumbnail::DIMENSIONS_READ_LIMIT, &state)
+                .await
+                .and_then(|x| thumbnail::dimensions(&x));
+        if let Some((width, height)) = maybe_dimensions {
+            let (thumbnail_width, thumbnail_height) =
+                thumbnail::fit_dimensions(width, height, state.config.thumbnail_size);
+            response["type"] = json!("photo");
+            response["url"] = json!(format!(
+                "{}{}",
+                base_url,
+                utils::get_download_link(info.id.clone())
+            ));
+            response["width"] = json!(width);
+            response["height"] = json!(height);
+            response["thumbnail_url"] = json!(format!(
+                "{}{}",
+                base_url,
+                utils::get_thumbnail_link(info.id.clone())
+            ));
+            response["thumbnail_width"] = json!(thumbnail_width);
+            response["thumbnail_height"] = json!(thumbnail_height);
+        }
+    }
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(boxed(response.to_string()))
+        .unwrap()
+}
+
 async fn index() -> Response {
     IntoResponse::into_response("API Is live")
 }
@@ -261,8 +1193,16 @@ pub fn get_api_router(config: AppConfig) -> Router<AppState> {
     Router::new()
         .route("/", get(index))
         .route("/file", post(upload))
+        .route("/paste", post(paste))
         .route("/file/:file", get(download)) // TODO: Cache system caching files under 10mb or similar
         .route("/file/:file", delete(erase))
+        .route("/file/:file", patch(edit))
+        .route("/file/:file/delete", post(erase))
+        .route("/file/:file/content", put(replace))
+        .route("/file/:file/thumb", get(thumbnail))
+        .route("/file/:file/report", post(report))
+        .route("/oembed", get(oembed))
+        .nest("/admin", admin::get_admin_router())
         .layer(DefaultBodyLimit::max(
             (config.file_size_limit.get_bytes() + 1024) as usize,
         ))
@@ -0,0 +1,86 @@
+/*
+Hashes of content that can't be uploaded again, usually after it was taken down.
+They're in their own sled tree, keyed by the hex encoded sha256 like `FileInfo.hash`.
+*/
+
+use std::error::Error;
+
+use bincode::{config::Configuration, decode_from_slice, encode_to_vec, Decode, Encode};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sled::{Db, Tree};
+
+const BINCODE_CONFIG: Configuration = bincode::config::standard();
+
+#[derive(Encode, Decode, Serialize, Debug, Clone)]
+pub struct BlocklistEntry {
+    pub hash: String,
+    #[bincode(with_serde)]
+    pub added: DateTime<Utc>,
+    /// Why it's blocked, for admins
+    pub reason: Option<String>,
+}
+
+fn tree(db: &Db) -> Result<Tree, Box<dyn Error>> {
+    Ok(db.open_tree("blocklist")?)
+}
+
+/// Lowercase hex sha256, or None if it isn't one
+pub fn normalize_hash(hash: &str) -> Option<String> {
+    let hash = hash.trim().to_lowercase();
+    (hash.len() == 64 && hash.chars().all(|x| x.is_ascii_hexdigit())).then_some(hash)
+}
+
+pub fn is_blocked(hash: &str, db: &Db) -> Result<bool, Box<dyn Error>> {
+    Ok(tree(db)?.contains_key(hash)?)
+}
+
+pub fn add(hash: &str, reason: Option<String>, db: &Db) -> Result<(), Box<dyn Error>> {
+    let entry = BlocklistEntry {
+        hash: hash.to_string(),
+        added: Utc::now(),
+        reason,
+    };
+    tree(db)?.insert(hash, encode_to_vec(&entry, BINCODE_CONFIG)?)?;
+    Ok(())
+}
+
+/// Returns false if the hash wasn't blocked
+pub fn remove(hash: &str, db: &Db) -> Result<bool, Box<dyn Error>> {
+    Ok(tree(db)?.remove(hash)?.is_some())
+}
+
+pub fn list(db: &Db) -> Result<Vec<BlocklistEntry>, Box<dyn Error>> {
+    let mut entries = vec![];
+    for maybe_pair in tree(db)?.iter() {
+        let (_, value) = maybe_pair?;
+        entries.push(decode_from_slice(&value, BINCODE_CONFIG)?.0);
+    }
+    Ok(entries)
+}
+
+/// Adds every hash in a hash list, like the output of sha256sum: one hash per line,
+/// optionally followed by a file name. Empty lines and lines starting with # are skipped.
+/// Returns how many hashes were added and which lines weren't understood.
+pub fn import(
+    hash_list: &str,
+    reason: Option<String>,
+    db: &Db,
+) -> Result<(usize, Vec<String>), Box<dyn Error>> {
+    let mut added = 0;
+    let mut invalid = vec![];
+    for line in hash_list.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let first_word = line.split_whitespace().next().unwrap_or_default();
+        match normalize_hash(first_word) {
+            Some(hash) => {
+                add(&hash, reason.clone(), db)?;
+                added += 1;
+            }
+            None => invalid.push(line.to_string()),
+        }
+    }
+    Ok((added, invalid))
+}
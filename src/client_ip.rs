@@ -0,0 +1,219 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header, request::Parts, HeaderMap, StatusCode},
+};
+
+use crate::{AppConfig, AppState, ForwardedHeader};
+
+/// Address of the client that made a request. Forwarded headers are only believed
+/// when the request comes from one of the `trusted_proxies`.
+pub struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(peer) = parts.extensions.get::<ConnectInfo<SocketAddr>>().ok_or((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Can't determine the client IP",
+        ))?;
+        Ok(Self(resolve(peer.ip(), &parts.headers, &state.config)))
+    }
+}
+
+fn is_trusted(ip: IpAddr, config: &AppConfig) -> bool {
+    let ip = ip.to_canonical();
+    config.trusted_proxies.iter().any(|x| x.contains(&ip))
+}
+
+/// Walks the forwarded chain from the closest hop backwards and stops at the first
+/// address we don't trust, since everything before it could be made up by the client.
+/// Hops we can't parse end the walk too, the last trusted address is used then.
+fn resolve(peer: IpAddr, headers: &HeaderMap, config: &AppConfig) -> IpAddr {
+    if !is_trusted(peer, config) {
+        return peer;
+    }
+    let chain = match config.forwarded_header {
+        ForwardedHeader::XForwardedFor => x_forwarded_for(headers),
+        ForwardedHeader::XRealIp => x_real_ip(headers),
+        ForwardedHeader::Forwarded => forwarded_for(headers),
+    };
+
+    let mut client = peer;
+    for hop in chain.into_iter().rev() {
+        let Some(hop) = hop else {
+            break;
+        };
+        client = hop;
+        if !is_trusted(hop, config) {
+            break;
+        }
+    }
+    client
+}
+
+/// Values of a header that can be sent more than once, split at commas
+fn header_list<'a>(headers: &'a HeaderMap, name: &str) -> impl Iterator<Item = &'a str> {
+    headers
+        .get_all(name)
+        .iter()
+        .map(|x| x.to_str().unwrap_or_default())
+        .flat_map(|x| x.split(','))
+        .map(str::trim)
+}
+
+/// `for` addresses of the standard Forwarded header, like
+/// `Forwarded: for=192.0.2.60;proto=http, for="[2001:db8:cafe::17]:4711"`.
+/// Obfuscated and unknown nodes are None.
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    header_list(headers, header::FORWARDED.as_str())
+        .map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.eq_ignore_ascii_case("for")
+                    .then(|| parse_node(value.trim_matches('"')))?
+            })
+        })
+        .collect()
+}
+
+/// Parses an address that can have a port and brackets around ipv6
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    node.parse().ok().or_else(|| {
+        node.rsplit_once(':')?
+            .0
+            .parse::<std::net::Ipv4Addr>()
+            .ok()
+            .map(IpAddr::V4)
+    })
+}
+
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    header_list(headers, "x-forwarded-for")
+        .map(parse_node)
+        .collect()
+}
+
+fn x_real_ip(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get("x-real-ip")
+        .map(|x| parse_node(x.to_str().unwrap_or_default().trim()))
+        .into_iter()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxy_config(header: ForwardedHeader) -> AppConfig {
+        AppConfig {
+            trusted_proxies: vec!["10.0.0.0/8".parse().unwrap()],
+            forwarded_header: header,
+            ..AppConfig::default()
+        }
+    }
+
+    fn request_headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn ip(x: &str) -> IpAddr {
+        x.parse().unwrap()
+    }
+
+    #[test]
+    fn untrusted_peer_is_the_client() {
+        let config = proxy_config(ForwardedHeader::XForwardedFor);
+        let headers = request_headers(&[("x-forwarded-for", "9.9.9.9")]);
+        assert_eq!(resolve(ip("1.2.3.4"), &headers, &config), ip("1.2.3.4"));
+    }
+
+    #[test]
+    fn takes_the_hop_the_proxy_appended() {
+        let config = proxy_config(ForwardedHeader::XForwardedFor);
+        // the client made up the first one, nginx appended the second
+        let headers = request_headers(&[("x-forwarded-for", "9.9.9.9, 1.2.3.4")]);
+        assert_eq!(resolve(ip("10.0.0.1"), &headers, &config), ip("1.2.3.4"));
+    }
+
+    #[test]
+    fn skips_trusted_hops() {
+        let config = proxy_config(ForwardedHeader::XForwardedFor);
+        let headers = request_headers(&[
+            ("x-forwarded-for", "9.9.9.9, 1.2.3.4"),
+            ("x-forwarded-for", "10.0.0.2"),
+        ]);
+        assert_eq!(resolve(ip("10.0.0.1"), &headers, &config), ip("1.2.3.4"));
+    }
+
+    #[test]
+    fn ignores_headers_that_arent_configured() {
+        let config = proxy_config(ForwardedHeader::XForwardedFor);
+        let headers = request_headers(&[
+            ("forwarded", "for=9.9.9.9"),
+            ("x-real-ip", "9.9.9.9"),
+            ("x-forwarded-for", "1.2.3.4"),
+        ]);
+        assert_eq!(resolve(ip("10.0.0.1"), &headers, &config), ip("1.2.3.4"));
+
+        let config = proxy_config(ForwardedHeader::Forwarded);
+        let headers = request_headers(&[("x-forwarded-for", "9.9.9.9")]);
+        assert_eq!(resolve(ip("10.0.0.1"), &headers, &config), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn stops_at_unparseable_hops() {
+        let config = proxy_config(ForwardedHeader::XForwardedFor);
+        let headers = request_headers(&[("x-forwarded-for", "9.9.9.9, unknown")]);
+        assert_eq!(resolve(ip("10.0.0.1"), &headers, &config), ip("10.0.0.1"));
+
+        let config = proxy_config(ForwardedHeader::Forwarded);
+        let headers = request_headers(&[("forwarded", "for=9.9.9.9, for=_hidden")]);
+        assert_eq!(resolve(ip("10.0.0.1"), &headers, &config), ip("10.0.0.1"));
+        let headers = request_headers(&[("forwarded", "for=9.9.9.9, for=10.0.0.3, for=unknown")]);
+        assert_eq!(resolve(ip("10.0.0.1"), &headers, &config), ip("10.0.0.1"));
+        let headers = request_headers(&[("forwarded", "for=9.9.9.9, proto=https")]);
+        assert_eq!(resolve(ip("10.0.0.1"), &headers, &config), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn parses_ports_and_brackets() {
+        let config = proxy_config(ForwardedHeader::XForwardedFor);
+        let headers = request_headers(&[("x-forwarded-for", "9.9.9.9, 1.2.3.4:5678")]);
+        assert_eq!(resolve(ip("10.0.0.1"), &headers, &config), ip("1.2.3.4"));
+
+        let config = proxy_config(ForwardedHeader::Forwarded);
+        let headers = request_headers(&[(
+            "forwarded",
+            "for=9.9.9.9, for=\"[2001:db8:cafe::17]:4711\";proto=https",
+        )]);
+        assert_eq!(
+            resolve(ip("10.0.0.1"), &headers, &config),
+            ip("2001:db8:cafe::17")
+        );
+    }
+
+    #[test]
+    fn x_real_ip_is_a_single_address() {
+        let config = proxy_config(ForwardedHeader::XRealIp);
+        let headers = request_headers(&[("x-real-ip", "1.2.3.4"), ("x-forwarded-for", "9.9.9.9")]);
+        assert_eq!(resolve(ip("10.0.0.1"), &headers, &config), ip("1.2.3.4"));
+        let headers = request_headers(&[("x-real-ip", "garbage")]);
+        assert_eq!(resolve(ip("10.0.0.1"), &headers, &config), ip("10.0.0.1"));
+    }
+}
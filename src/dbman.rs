@@ -1,30 +1,59 @@
-use std::{error::Error, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
 
 use async_compression::tokio::{bufread::BrotliDecoder, write::BrotliEncoder};
 use bincode::{serde::decode_from_slice, Decode, Encode};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sha3::{Digest, Sha3_512};
-use sled::Db;
+use sha2::{Digest, Sha256};
+use sled::{Batch, Db};
 use tokio::{
     fs::{self, File},
-    io::{AsyncWriteExt, BufReader, BufStream},
+    io::{
+        AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter,
+    },
+    sync::OwnedMutexGuard,
 };
 
-use crate::AppState;
+use crate::{
+    encryption::{self, DecryptReader, EncryptWriter},
+    reports,
+    scanner::ScanResult,
+    sniff,
+    utils::{content_hash, hash_deletion_key, unique_id},
+    AppConfig, AppState,
+};
 
 /*
 # Custom database using sled
 
 Metadata is stored with a key like this: `metadata:[ID]`
-The value is just the FileInfo struct encoded with bincode
+The value is RECORD_MARKER, a version byte and then the FileInfo struct encoded with bincode.
+Records from before there were versions are just the bincode, see LegacyFileInfo.
+
+When a files contents are replaced, the FileInfo of the old revision is kept with a key
+like this: `revision:[ID]:[REVISION]`. Revision 0 is stored in `[ID].br`, later ones in
+`[ID].r[REVISION].br`, so readers never see a half written file.
+
+Download counts are stored apart from the metadata as a u64 in little endian,
+with a key like this: `downloads:[ID]`
+
+With encryption turned on, blobs and the caches next to them are encrypted below the
+brotli layer, see encryption.rs. Their data keys are in the `data_keys` tree.
 */
 
-#[derive(Encode, Decode, Deserialize, Serialize, PartialEq, Eq, Debug)]
+#[derive(Encode, Decode, Deserialize, Serialize, PartialEq, Eq, Debug, Clone)]
 pub struct FileInfo {
-    /// Should be a valid mime type
+    /// Should be a valid mime type. Claimed by the uploader, so it can't be trusted.
     pub mime_type: String,
 
+    /// Detected from the contents, this one decides if the file is shown inline
+    pub detected_mime_type: String,
+
     /// Date of file upload
     #[bincode(with_serde)]
     pub upload_date: DateTime<Utc>,
@@ -40,19 +69,318 @@ pub struct FileInfo {
 
     // Size of the file in bytes
     pub size: usize,
+
+    /// Hex encoded sha256 of the file contents
+    pub hash: String,
+
+    /// The file is deleted after this date, if there is one
+    #[bincode(with_serde)]
+    pub expiry_date: Option<DateTime<Utc>>,
+
+    /// Set by the uploader, shown on the file page
+    pub description: Option<String>,
+
+    /// If false the file is never shown inline, even if its mime type allows it
+    pub preview: bool,
+
+    /// Hashed password needed to see the file, if it's protected.
+    /// Metadata is decoded with serde, so this can't be skipped when deserializing.
+    #[serde(skip_serializing)]
+    pub password_hash: Option<String>,
+
+    /// Starts at 0 and goes up every time the contents are replaced
+    pub revision: u32,
+
+    /// Files that aren't clean are quarantined and can't be downloaded
+    pub scan_result: ScanResult,
+
+    /// Hidden after being reported too often, until a moderator looks at it
+    pub hidden: bool,
+}
+
+impl FileInfo {
+    pub fn is_quarantined(&self) -> bool {
+        !self.scan_result.is_clean()
+    }
+
+    /// Withheld files are kept around, but their contents can't be seen
+    pub fn is_withheld(&self) -> bool {
+        self.is_quarantined() || self.hidden
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expiry_date.is_some_and(|x| x <= Utc::now())
+    }
+
+    pub fn is_valid_deletion_key(&self, deletion_key: &str) -> bool {
+        hash_deletion_key(deletion_key, &self.name) == self.deletion_key
+    }
+}
+
+/// FileInfo as it was stored before records had a version.
+/// Only decoded, and upgraded to the current format by `upgrade_legacy_records`.
+#[derive(Deserialize)]
+struct LegacyFileInfo {
+    mime_type: String,
+    upload_date: DateTime<Utc>,
+    deletion_key: String,
+    id: String,
+    name: String,
+    size: usize,
+}
+
+impl From<LegacyFileInfo> for FileInfo {
+    fn from(legacy: LegacyFileInfo) -> Self {
+        FileInfo {
+            mime_type: legacy.mime_type,
+            // not shown inline until the upgrade sniffs the contents
+            detected_mime_type: sniff::BINARY_MIME_TYPE.to_string(),
+            upload_date: legacy.upload_date,
+            deletion_key: legacy.deletion_key,
+            id: legacy.id,
+            name: legacy.name,
+            size: legacy.size,
+            hash: String::new(),
+            expiry_date: None,
+            description: None,
+            preview: true,
+            password_hash: None,
+            revision: 0,
+            scan_result: ScanResult::NotScanned,
+            hidden: false,
+        }
+    }
 }
 
 const BINCODE_CONFIG: bincode::config::Configuration = bincode::config::standard();
 
+/// Legacy records start with the varint length of the mime type, bincode never starts one with 0xFF
+const RECORD_MARKER: u8 = 0xFF;
+const RECORD_VERSION: u8 = 1;
+
+fn encode_file_info(file_info: &FileInfo) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut encoded = vec![RECORD_MARKER, RECORD_VERSION];
+    encoded.extend(bincode::encode_to_vec(file_info, BINCODE_CONFIG)?);
+    Ok(encoded)
+}
+
+fn is_legacy_record(encoded: &[u8]) -> bool {
+    encoded.first().is_some_and(|&x| x != RECORD_MARKER)
+}
+
+/// Decodes both versioned and legacy records.
+/// Reserved ids have empty metadata, those decode to None like anything else that's broken.
+fn decode_file_info(encoded: &[u8]) -> Option<FileInfo> {
+    match encoded {
+        [] => None,
+        [RECORD_MARKER, RECORD_VERSION, rest @ ..] => {
+            Some(decode_from_slice(rest, BINCODE_CONFIG).ok()?.0)
+        }
+        [RECORD_MARKER, ..] => None,
+        legacy => Some(
+            decode_from_slice::<LegacyFileInfo, _>(legacy, BINCODE_CONFIG)
+                .ok()?
+                .0
+                .into(),
+        ),
+    }
+}
+
+/// The metadata as it's stored, even if the file expired
+fn read_stored_file_info(id: &str, db: &Db) -> Option<FileInfo> {
+    decode_file_info(&db.get(format!("metadata:{}", id)).ok()??)
+}
+
+/// Expired files are treated as if they don't exist, even before they're deleted.
 pub fn read_file_info(id: String, db: &Db) -> Option<FileInfo> {
-    let encoded_file_info: &[u8] = &db.get(format!("metadata:{}", id)).ok()??;
-    let file_info: FileInfo = decode_from_slice(encoded_file_info, BINCODE_CONFIG).ok()?.0;
+    let file_info = read_stored_file_info(&id, db)?;
+    if file_info.is_expired() {
+        return None;
+    }
     log::debug!("Read file info {}", file_info.id);
     Some(file_info)
 }
 
-fn file_path_from_id(id: &str, state: &AppState) -> PathBuf {
-    state.priv_config.blob_path.join(format!("{}.br", id))
+/// Claims an id for a new file, returns false if it's already in use.
+/// Until the file is stored its metadata is empty, which reads as if it doesn't exist.
+pub fn reserve_id(id: &str, db: &Db) -> Result<bool, Box<dyn Error>> {
+    let swapped = db.compare_and_swap(
+        format!("metadata:{}", id),
+        None as Option<&[u8]>,
+        Some(&[] as &[u8]),
+    )?;
+    Ok(swapped.is_ok())
+}
+
+/// Picks and reserves a random id for a new file, retrying on collisions.
+pub fn reserve_new_id(config: &AppConfig, db: &Db) -> Result<String, Box<dyn Error>> {
+    for _ in 0..8 {
+        let id = unique_id(config);
+        if reserve_id(&id, db)? {
+            return Ok(id);
+        }
+        log::warn!("Generated id {} was already taken", id);
+    }
+    Err("couldn't find a free id, the ids are too short".into())
+}
+
+/// One lock per file whose metadata is being changed
+static FILE_LOCKS: Mutex<BTreeMap<String, Arc<tokio::sync::Mutex<()>>>> =
+    Mutex::new(BTreeMap::new());
+
+/// Held while the metadata of a file is read, changed and written back, so changes to the
+/// same file can't overwrite each other. It's forgotten once nobody is waiting for it.
+pub struct FileLock {
+    id: String,
+    _guard: OwnedMutexGuard<()>,
+}
+
+impl FileLock {
+    pub async fn lock(id: &str) -> FileLock {
+        let lock = FILE_LOCKS
+            .lock()
+            .unwrap()
+            .entry(id.to_string())
+            .or_default()
+            .clone();
+        FileLock {
+            id: id.to_string(),
+            _guard: lock.lock_owned().await,
+        }
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let mut locks = FILE_LOCKS.lock().unwrap();
+        // one for the map and one for this guard, anything more is someone waiting
+        if locks
+            .get(&self.id)
+            .is_some_and(|x| Arc::strong_count(x) == 2)
+        {
+            locks.remove(&self.id);
+        }
+    }
+}
+
+/// Overwrites the metadata of a file. Read it while holding the lock too,
+/// otherwise changes made in between are lost.
+pub fn write_file_info(
+    file_info: &FileInfo,
+    lock: &FileLock,
+    db: &Db,
+) -> Result<(), Box<dyn Error>> {
+    if lock.id != file_info.id {
+        return Err("holding the lock of another file".into());
+    }
+    let encoded_file_info = encode_file_info(file_info)?;
+    db.insert(format!("metadata:{}", file_info.id), encoded_file_info)?;
+    log::debug!("Wrote file info {}", file_info.id);
+    Ok(())
+}
+
+/// Reads the FileInfo of a revision, which can also be the current one.
+pub fn read_revision_info(id: String, revision: u32, db: &Db) -> Option<FileInfo> {
+    let current = read_file_info(id.clone(), db)?;
+    if current.revision == revision {
+        return Some(current);
+    }
+    let encoded_file_info: &[u8] = &db.get(format!("revision:{}:{}", id, revision)).ok()??;
+    decode_file_info(encoded_file_info)
+}
+
+fn file_path_from_id(id: &str, revision: u32, state: &AppState) -> PathBuf {
+    if revision == 0 {
+        state.priv_config.blob_path.join(format!("{}.br", id))
+    } else {
+        state
+            .priv_config
+            .blob_path
+            .join(format!("{}.r{}.br", id, revision))
+    }
+}
+
+/// Blobs are read through this, whether they're encrypted or not
+pub type BlobReader = BufReader<Box<dyn AsyncRead + Unpin + Send>>;
+
+/// Name of a file in the blob directory, data keys are stored under it
+fn blob_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Creates a file in the blob directory, encrypted if encryption is turned on.
+/// The writer has to be shut down when done.
+async fn create_blob(
+    path: &Path,
+    state: &AppState,
+) -> Result<Box<dyn AsyncWrite + Unpin + Send>, Box<dyn Error>> {
+    let writer = BufWriter::new(File::create(path).await?);
+    match &state.keyring {
+        Some(keyring) => {
+            let data_key = encryption::new_data_key(&blob_name(path), keyring, &state.db)?;
+            Ok(Box::new(EncryptWriter::new(writer, &data_key)))
+        }
+        None => Ok(Box::new(writer)),
+    }
+}
+
+/// Opens a file in the blob directory and decrypts it if it's encrypted,
+/// along with its length after decryption.
+async fn open_blob(path: &Path, state: &AppState) -> Result<(BlobReader, u64), Box<dyn Error>> {
+    let file = File::open(path).await?;
+    let length = file.metadata().await?.len();
+    let mut reader = BufReader::new(file);
+
+    // blobs from before encryption was turned on are read as they are
+    if !reader.fill_buf().await?.starts_with(encryption::MAGIC) {
+        return Ok((BufReader::new(Box::new(reader)), length));
+    }
+
+    let keyring = state
+        .keyring
+        .as_ref()
+        .ok_or("blob is encrypted, but there's no encryption key")?;
+    let data_key = encryption::data_key(&blob_name(path), keyring, &state.db)?;
+    let mut header = [0; encryption::HEADER_SIZE];
+    reader.read_exact(&mut header).await?;
+    let encrypted_length = length.saturating_sub(header.len() as u64);
+    let decrypted = DecryptReader::new(reader, &data_key, &header, encrypted_length)?;
+    Ok((
+        BufReader::new(Box::new(decrypted)),
+        encryption::plaintext_length(encrypted_length),
+    ))
+}
+
+/// Removes a file from the blob directory along with its data key.
+/// A blob that's already gone counts as removed.
+async fn remove_blob(path: &Path, state: &AppState) -> Result<(), Box<dyn Error>> {
+    let removed = fs::remove_file(path).await;
+    encryption::forget_data_key(&blob_name(path), &state.db)?;
+    match removed {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+// write file to DB_PATH/blob/id.br using brotli compression
+async fn write_blob(
+    file: &[u8],
+    id: &str,
+    revision: u32,
+    state: &AppState,
+) -> Result<(), Box<dyn Error>> {
+    let target_file_path = file_path_from_id(id, revision, state);
+
+    let mut writer = BrotliEncoder::with_quality(
+        create_blob(&target_file_path, state).await?,
+        async_compression::Level::Fastest,
+    );
+    writer.write_all(file).await?;
+    writer.shutdown().await?;
+    Ok(())
 }
 
 // TODO: maybe make this buf (stream) for perf, if possible
@@ -61,20 +389,9 @@ pub async fn store_file(
     file_info: &FileInfo,
     state: &AppState,
 ) -> Result<(), Box<dyn Error>> {
-    let encoded_file_info = bincode::encode_to_vec(&file_info, BINCODE_CONFIG)?;
+    let encoded_file_info = encode_file_info(file_info)?;
 
-    // write file to DB_PATH/blob/id.br using brotli compression
-    {
-        let target_file_path = file_path_from_id(&file_info.id, &state);
-
-        let target_file = File::create(target_file_path).await?;
-        let mut writer = BrotliEncoder::with_quality(
-            tokio::io::BufWriter::new(target_file),
-            async_compression::Level::Fastest,
-        );
-        writer.write_all(&file).await?;
-        writer.shutdown().await?;
-    }
+    write_blob(&file, &file_info.id, file_info.revision, state).await?;
 
     state
         .db
@@ -83,34 +400,176 @@ pub async fn store_file(
     Ok(())
 }
 
+/// Stores new contents for a file as a new revision, and keeps the old one around.
+/// The new blob is fully written before the metadata points to it.
+pub async fn replace_file(
+    file: Vec<u8>,
+    id: String,
+    mime_type: Option<String>,
+    scan_result: ScanResult,
+    state: &AppState,
+) -> Result<FileInfo, Box<dyn Error>> {
+    let _lock = FileLock::lock(&id).await;
+
+    let old_info = read_file_info(id, &state.db).ok_or("couldn't find file with specified id")?;
+    let new_info = FileInfo {
+        mime_type: mime_type.unwrap_or_else(|| old_info.mime_type.clone()),
+        detected_mime_type: sniff::detect(&file),
+        size: file.len(),
+        hash: content_hash(&file),
+        revision: old_info.revision + 1,
+        scan_result,
+        ..old_info.clone()
+    };
+
+    write_blob(&file, &new_info.id, new_info.revision, state).await?;
+
+    // everything else that changes the file takes the lock too, but better safe than
+    // bringing back a deleted file without its blobs
+    if read_stored_file_info(&new_info.id, &state.db).as_ref() != Some(&old_info) {
+        let _ = remove_blob(
+            &file_path_from_id(&new_info.id, new_info.revision, state),
+            state,
+        )
+        .await;
+        return Err("the file changed while it was being replaced".into());
+    }
+
+    let mut batch = Batch::default();
+    batch.insert(
+        format!("revision:{}:{}", old_info.id, old_info.revision).as_bytes(),
+        encode_file_info(&old_info)?,
+    );
+    batch.insert(
+        format!("metadata:{}", new_info.id).as_bytes(),
+        encode_file_info(&new_info)?,
+    );
+    state.db.apply_batch(batch)?;
+
+    // these were generated from the old contents
+    let _ = remove_blob(&highlight_cache_path_from_id(&new_info.id, state), state).await;
+    let _ = remove_blob(&thumbnail_path_from_id(&new_info.id, state), state).await;
+
+    log::debug!(
+        "Replaced file {} with revision {}",
+        new_info.id,
+        new_info.revision
+    );
+    Ok(new_info)
+}
+
 pub async fn decode(
-    encoded: BufReader<File>,
-) -> Result<
-    BufReader<async_compression::tokio::bufread::BrotliDecoder<BufStream<BufReader<File>>>>,
-    Box<dyn Error>,
-> {
-    let reader = BufStream::new(encoded);
-    let decoder = BrotliDecoder::new(reader);
+    encoded: BlobReader,
+) -> Result<BufReader<BrotliDecoder<BlobReader>>, Box<dyn Error>> {
+    let decoder = BrotliDecoder::new(encoded);
     log::debug!("Decoding brotli stream");
     Ok(BufReader::new(decoder))
 }
 
-pub async fn read_file(id: String, state: &AppState) -> Option<(BufReader<File>, u64)> {
-    let brotli_blob_file_path = state.priv_config.blob_path.join(format!("{}.br", id));
+/// The brotli stream of a file and its length
+pub async fn read_file(file_info: &FileInfo, state: &AppState) -> Option<(BlobReader, u64)> {
+    let brotli_blob_file_path = file_path_from_id(&file_info.id, file_info.revision, state);
 
-    let brotli_blob_file = File::open(brotli_blob_file_path).await.ok()?;
+    let (buffer, length) = match open_blob(&brotli_blob_file_path, state).await {
+        Ok(x) => x,
+        Err(e) => {
+            log::error!("Couldn't open blob of {}: {}", file_info.id, e);
+            return None;
+        }
+    };
 
-    let length = brotli_blob_file
-        .metadata()
-        .await
-        .expect("Couldn't read file metadata")
-        .len();
+    log::debug!("Read file {}", file_info.id);
+
+    Some((buffer, length))
+}
 
-    let buffer = BufReader::new(brotli_blob_file);
+/// Reads and decodes the whole file into memory, only use this for small files.
+pub async fn read_file_bytes(file_info: &FileInfo, state: &AppState) -> Option<Vec<u8>> {
+    let (buffer, _) = read_file(file_info, state).await?;
+    let mut decoder = decode(buffer).await.ok()?;
+    let mut bytes = vec![];
+    decoder.read_to_end(&mut bytes).await.ok()?;
+    Some(bytes)
+}
 
-    log::debug!("Read file {}", id);
+/// Reads and decodes only the first `limit` bytes of a file, enough for things like image headers.
+pub async fn read_file_start(
+    file_info: &FileInfo,
+    limit: u64,
+    state: &AppState,
+) -> Option<Vec<u8>> {
+    let (buffer, _) = read_file(file_info, state).await?;
+    let decoder = decode(buffer).await.ok()?;
+    let mut bytes = vec![];
+    decoder.take(limit).read_to_end(&mut bytes).await.ok()?;
+    Some(bytes)
+}
 
-    Some((buffer, length))
+fn highlight_cache_path_from_id(id: &str, state: &AppState) -> PathBuf {
+    state.priv_config.blob_path.join(format!("{}.hl.json", id))
+}
+
+/// Reads a whole cache file, caches are blobs too so they're encrypted like the files
+async fn read_cache(path: &Path, state: &AppState) -> Option<Vec<u8>> {
+    let (mut reader, _) = open_blob(path, state).await.ok()?;
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes).await.ok()?;
+    Some(bytes)
+}
+
+async fn write_cache(path: &Path, bytes: &[u8], state: &AppState) -> Result<(), Box<dyn Error>> {
+    let mut writer = create_blob(path, state).await?;
+    writer.write_all(bytes).await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// Reads the cached syntax highlighted lines of a file, if there are any.
+pub async fn read_highlight_cache(id: &str, state: &AppState) -> Option<Vec<String>> {
+    let encoded = read_cache(&highlight_cache_path_from_id(id, state), state).await?;
+    serde_json::from_slice(&encoded).ok()
+}
+
+/// The highlighting depends on the name and mime type, so it's thrown away when they change
+pub async fn remove_highlight_cache(id: &str, state: &AppState) -> Result<(), Box<dyn Error>> {
+    remove_blob(&highlight_cache_path_from_id(id, state), state).await
+}
+
+pub async fn write_highlight_cache(
+    id: &str,
+    lines: &[String],
+    state: &AppState,
+) -> Result<(), Box<dyn Error>> {
+    write_cache(
+        &highlight_cache_path_from_id(id, state),
+        &serde_json::to_vec(lines)?,
+        state,
+    )
+    .await?;
+    log::debug!("Wrote highlight cache of {}", id);
+    Ok(())
+}
+
+fn thumbnail_path_from_id(id: &str, state: &AppState) -> PathBuf {
+    state
+        .priv_config
+        .blob_path
+        .join(format!("{}.thumb.png", id))
+}
+
+/// An empty thumbnail means generating one failed before, so it isn't tried again
+pub async fn read_thumbnail(id: &str, state: &AppState) -> Option<Vec<u8>> {
+    read_cache(&thumbnail_path_from_id(id, state), state).await
+}
+
+pub async fn write_thumbnail(
+    id: &str,
+    thumbnail: &[u8],
+    state: &AppState,
+) -> Result<(), Box<dyn Error>> {
+    write_cache(&thumbnail_path_from_id(id, state), thumbnail, state).await?;
+    log::debug!("Wrote thumbnail of {}", id);
+    Ok(())
 }
 
 pub async fn delete_file(
@@ -120,27 +579,255 @@ pub async fn delete_file(
 ) -> Result<bool, Box<dyn Error>> {
     let file_info = read_file_info(id, &state.db).ok_or("couldn't find file with specified id")?;
 
-    let mut hasher = Sha3_512::new();
+    if !file_info.is_valid_deletion_key(&actual_deletion_key) {
+        return Ok(false);
+    }
+
+    remove_file(&file_info, state).await?;
 
-    hasher.update(&actual_deletion_key);
-    hasher.update(&file_info.name); // salt, idk if needed but why not
+    Ok(true)
+}
 
-    let hashed_deletion_key_raw = hasher.finalize();
+/// Removes a file and everything stored about it, without checking any keys.
+pub async fn remove_file(file_info: &FileInfo, state: &AppState) -> Result<(), Box<dyn Error>> {
+    let id = &file_info.id;
+    let _lock = FileLock::lock(id).await;
+    // it could have gotten new revisions since `file_info` was read
+    let file_info = &read_stored_file_info(id, &state.db).unwrap_or_else(|| file_info.clone());
 
-    let hashed_deletion_key =
-        base64::encode_config(hashed_deletion_key_raw, base64::URL_SAFE).replace('=', "");
+    let target_file_path = file_path_from_id(id, file_info.revision, state);
 
-    let valid_deletion_key = hashed_deletion_key == file_info.deletion_key;
+    remove_blob(&target_file_path, state).await?;
 
-    if !valid_deletion_key {
-        return Ok(false);
+    for revision in 0..file_info.revision {
+        let _ = remove_blob(&file_path_from_id(id, revision, state), state).await;
+        state.db.remove(format!("revision:{}:{}", id, revision))?;
     }
 
-    let target_file_path = file_path_from_id(&file_info.id, &state);
+    // these are generated lazily, so they might not exist
+    let _ = remove_blob(&highlight_cache_path_from_id(id, state), state).await;
+    let _ = remove_blob(&thumbnail_path_from_id(id, state), state).await;
 
-    fs::remove_file(target_file_path).await?;
+    state.db.remove(format!("metadata:{}", id))?;
+    state.db.remove(format!("downloads:{}", id))?;
+    reports::remove_for_file(id, &state.db)?;
 
-    state.db.remove(format!("metadata:{}", file_info.id))?;
+    log::debug!("Removed file {}", id);
+    Ok(())
+}
 
-    Ok(true)
+pub fn increment_download_count(id: &str, db: &Db) -> Result<(), Box<dyn Error>> {
+    db.update_and_fetch(format!("downloads:{}", id), |old| {
+        let count = old
+            .and_then(|x| x.try_into().ok())
+            .map(u64::from_le_bytes)
+            .unwrap_or(0);
+        Some((count + 1).to_le_bytes().to_vec())
+    })?;
+    Ok(())
+}
+
+pub fn read_download_count(id: &str, db: &Db) -> u64 {
+    db.get(format!("downloads:{}", id))
+        .ok()
+        .flatten()
+        .and_then(|x| x.as_ref().try_into().ok())
+        .map(u64::from_le_bytes)
+        .unwrap_or(0)
+}
+
+/// Deletes every expired file, returns how many were deleted.
+pub async fn delete_expired_files(state: &AppState) -> Result<usize, Box<dyn Error>> {
+    let mut expired = vec![];
+    for maybe_pair in state.db.scan_prefix("metadata:") {
+        let (_, encoded_file_info) = maybe_pair?;
+        // reserved ids don't have any metadata yet
+        let Some(file_info) = decode_file_info(&encoded_file_info) else {
+            continue;
+        };
+        if file_info.is_expired() {
+            expired.push(file_info);
+        }
+    }
+
+    // one broken file shouldn't keep the rest around forever
+    let mut deleted = 0;
+    for file_info in &expired {
+        match remove_file(file_info, state).await {
+            Ok(()) => deleted += 1,
+            Err(e) => log::error!("Couldn't delete expired file {}: {}", file_info.id, e),
+        }
+    }
+
+    Ok(deleted)
+}
+
+/// Hashes and sniffs the contents of a legacy file, the fields that didn't exist back then
+async fn upgrade_legacy_file_info(
+    file_info: &mut FileInfo,
+    state: &AppState,
+) -> Result<(), Box<dyn Error>> {
+    let (buffer, _) = read_file(file_info, state)
+        .await
+        .ok_or("couldn't open blob")?;
+    let mut decoder = decode(buffer).await?;
+    let mut hasher = Sha256::new();
+    let mut start = vec![];
+    let mut chunk = vec![0; 64 * 1024];
+    loop {
+        let read = decoder.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&chunk[..read]);
+        if start.len() < sniff::TEXT_SNIFF_LENGTH {
+            let wanted = (sniff::TEXT_SNIFF_LENGTH - start.len()).min(read);
+            start.extend_from_slice(&chunk[..wanted]);
+        }
+    }
+    file_info.hash = format!("{:x}", hasher.finalize());
+    file_info.detected_mime_type = sniff::detect(&start);
+    Ok(())
+}
+
+/// Rewrites metadata from before records had a version, returns how many were upgraded.
+/// Files that can't be upgraded are logged and left as they are, they still decode fine.
+pub async fn upgrade_legacy_records(state: &AppState) -> Result<usize, Box<dyn Error>> {
+    let mut upgraded = 0;
+    for maybe_pair in state.db.scan_prefix("metadata:") {
+        let (key, encoded_file_info) = maybe_pair?;
+        if !is_legacy_record(&encoded_file_info) {
+            continue;
+        }
+        let Some(mut file_info) = decode_file_info(&encoded_file_info) else {
+            log::warn!(
+                "Couldn't decode legacy record {}",
+                String::from_utf8_lossy(&key)
+            );
+            continue;
+        };
+        if let Err(e) = upgrade_legacy_file_info(&mut file_info, state).await {
+            log::error!("Couldn't upgrade legacy file {}: {}", file_info.id, e);
+            continue;
+        }
+        // the file might have been changed or deleted in the meantime
+        let swapped = state.db.compare_and_swap(
+            key,
+            Some(encoded_file_info),
+            Some(encode_file_info(&file_info)?),
+        )?;
+        if swapped.is_ok() {
+            upgraded += 1;
+        }
+    }
+    Ok(upgraded)
+}
+
+/// Deletes expired files every now and then, never returns.
+pub async fn expiry_sweeper(state: AppState) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 10));
+    loop {
+        interval.tick().await;
+        match delete_expired_files(&state).await {
+            Ok(0) => {}
+            Ok(x) => log::info!("Deleted {} expired files", x),
+            Err(e) => log::error!("Couldn't delete expired files: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What the baseline wrote for every file
+    #[derive(Encode)]
+    struct OldFileInfo {
+        mime_type: String,
+        #[bincode(with_serde)]
+        upload_date: DateTime<Utc>,
+        deletion_key: String,
+        id: String,
+        name: String,
+        size: usize,
+    }
+
+    fn file_info() -> FileInfo {
+        FileInfo {
+            mime_type: "text/plain".to_string(),
+            detected_mime_type: "text/plain".to_string(),
+            upload_date: Utc::now(),
+            deletion_key: "key".to_string(),
+            id: "abc".to_string(),
+            name: "a.txt".to_string(),
+            size: 3,
+            hash: "hash".to_string(),
+            expiry_date: Some(Utc::now()),
+            description: Some("description".to_string()),
+            preview: false,
+            password_hash: Some("password".to_string()),
+            revision: 2,
+            scan_result: ScanResult::Clean,
+            hidden: true,
+        }
+    }
+
+    #[test]
+    fn versioned_round_trip() {
+        let info = file_info();
+        let encoded = encode_file_info(&info).unwrap();
+        assert!(!is_legacy_record(&encoded));
+        assert_eq!(decode_file_info(&encoded), Some(info));
+    }
+
+    #[test]
+    fn legacy_records_decode() {
+        let old = OldFileInfo {
+            // long enough that the length isn't a single varint byte
+            mime_type: "x".repeat(300),
+            upload_date: Utc::now(),
+            deletion_key: "key".to_string(),
+            id: "abc".to_string(),
+            name: "a.txt".to_string(),
+            size: 3,
+        };
+        let encoded = bincode::encode_to_vec(&old, BINCODE_CONFIG).unwrap();
+        assert!(is_legacy_record(&encoded));
+
+        let info = decode_file_info(&encoded).unwrap();
+        assert_eq!(info.mime_type, old.mime_type);
+        assert_eq!(info.upload_date, old.upload_date);
+        assert_eq!(info.id, "abc");
+        assert_eq!(info.size, 3);
+        assert_eq!(info.revision, 0);
+        assert!(!info.is_withheld());
+        assert!(!info.is_expired());
+    }
+
+    #[tokio::test]
+    async fn file_locks_are_per_file() {
+        let wait = std::time::Duration::from_millis(50);
+        let first = FileLock::lock("lock-test-a").await;
+        assert!(tokio::time::timeout(wait, FileLock::lock("lock-test-b"))
+            .await
+            .is_ok());
+        assert!(tokio::time::timeout(wait, FileLock::lock("lock-test-a"))
+            .await
+            .is_err());
+
+        drop(first);
+        let locks = FILE_LOCKS.lock().unwrap();
+        assert!(!locks.contains_key("lock-test-a"));
+        assert!(!locks.contains_key("lock-test-b"));
+    }
+
+    #[test]
+    fn reserved_and_unknown_records_dont_decode() {
+        assert_eq!(decode_file_info(&[]), None);
+        assert!(!is_legacy_record(&[]));
+
+        let mut encoded = encode_file_info(&file_info()).unwrap();
+        encoded[1] = RECORD_VERSION + 1;
+        assert_eq!(decode_file_info(&encoded), None);
+    }
 }
@@ -0,0 +1,522 @@
+/*
+# Encryption at rest
+
+Every blob gets its own random data key. Data keys are stored in the `data_keys` tree under
+the blob's file name, wrapped (encrypted) with the master key from the config.
+Blobs are encrypted with XChaCha20-Poly1305 in segments, using the STREAM construction,
+so they can be streamed without having the whole file in memory. An encrypted blob looks like
+`[MAGIC][19 byte nonce prefix][segment][segment]...`. Every segment is SEGMENT_SIZE bytes
+plus a 16 byte tag, except the last one which can be shorter.
+
+Blobs written before encryption was turned on don't start with MAGIC and are read as they are.
+Rotating the master key only means wrapping the data keys again, see `rewrap_data_keys`.
+*/
+
+use std::{
+    error::Error,
+    fs, io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bincode::{config::Configuration, decode_from_slice, encode_to_vec, Decode, Encode};
+use chacha20poly1305::{
+    aead::{
+        stream::{DecryptorBE32, EncryptorBE32},
+        Aead, KeyInit, Payload,
+    },
+    Key, XChaCha20Poly1305, XNonce,
+};
+use rand::{rngs::OsRng, RngCore};
+use sled::{Db, IVec, Tree};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{utils::content_hash, AppConfig};
+
+const BINCODE_CONFIG: Configuration = bincode::config::standard();
+
+/// Start of every encrypted blob
+pub const MAGIC: &[u8; 8] = b"FBENC\x00\x00\x01";
+const NONCE_PREFIX_SIZE: usize = 19;
+pub const HEADER_SIZE: usize = MAGIC.len() + NONCE_PREFIX_SIZE;
+
+/// Plaintext bytes per segment
+const SEGMENT_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+
+struct MasterKey {
+    /// Start of the sha256 of the key, so wrapped data keys know which master key they need
+    id: String,
+    cipher: XChaCha20Poly1305,
+}
+
+impl MasterKey {
+    fn parse(encoded: &str) -> Result<MasterKey, Box<dyn Error>> {
+        let bytes = base64::decode(encoded.trim())?;
+        if bytes.len() != 32 {
+            return Err("encryption keys have to be 32 bytes, base64 encoded".into());
+        }
+        Ok(MasterKey {
+            id: content_hash(&bytes)[..16].to_string(),
+            cipher: XChaCha20Poly1305::new(Key::from_slice(&bytes)),
+        })
+    }
+}
+
+/// The master key new data keys are wrapped with, and older ones that are still accepted
+pub struct Keyring {
+    current: MasterKey,
+    previous: Vec<MasterKey>,
+}
+
+impl Keyring {
+    /// None if encryption isn't turned on
+    pub fn from_config(config: &AppConfig) -> Result<Option<Keyring>, Box<dyn Error>> {
+        let encoded = match (&config.encryption_key, &config.encryption_key_file) {
+            (Some(_), Some(_)) => {
+                return Err("set either encryption_key or encryption_key_file, not both".into())
+            }
+            (Some(x), None) => x.clone(),
+            (None, Some(path)) => fs::read_to_string(path)?,
+            (None, None) if config.previous_encryption_keys.is_empty() => return Ok(None),
+            (None, None) => return Err("previous_encryption_keys need a current key".into()),
+        };
+        Ok(Some(Keyring {
+            current: MasterKey::parse(&encoded)?,
+            previous: config
+                .previous_encryption_keys
+                .iter()
+                .map(|x| MasterKey::parse(x))
+                .collect::<Result<_, _>>()?,
+        }))
+    }
+
+    fn find(&self, id: &str) -> Option<&MasterKey> {
+        std::iter::once(&self.current)
+            .chain(&self.previous)
+            .find(|x| x.id == id)
+    }
+}
+
+#[derive(Encode, Decode)]
+struct WrappedKey {
+    master_key_id: String,
+    nonce: [u8; 24],
+    wrapped: Vec<u8>,
+}
+
+impl WrappedKey {
+    /// The blob name is authenticated too, so keys can't be swapped between blobs
+    fn wrap(data_key: &Key, blob_name: &str, master_key: &MasterKey) -> io::Result<WrappedKey> {
+        let mut nonce = [0; 24];
+        OsRng.fill_bytes(&mut nonce);
+        let payload = Payload {
+            msg: data_key.as_slice(),
+            aad: blob_name.as_bytes(),
+        };
+        let wrapped = master_key
+            .cipher
+            .encrypt(XNonce::from_slice(&nonce), payload)
+            .map_err(|_| io::Error::other("couldn't wrap data key"))?;
+        Ok(WrappedKey {
+            master_key_id: master_key.id.clone(),
+            nonce,
+            wrapped,
+        })
+    }
+
+    fn unwrap(&self, blob_name: &str, keyring: &Keyring) -> io::Result<Key> {
+        let master_key = keyring.find(&self.master_key_id).ok_or_else(|| {
+            io::Error::other(format!(
+                "data key of {} needs master key {}, which isn't configured",
+                blob_name, self.master_key_id
+            ))
+        })?;
+        let payload = Payload {
+            msg: &self.wrapped,
+            aad: blob_name.as_bytes(),
+        };
+        let data_key = master_key
+            .cipher
+            .decrypt(XNonce::from_slice(&self.nonce), payload)
+            .map_err(|_| io::Error::other(format!("couldn't unwrap data key of {}", blob_name)))?;
+        Ok(*Key::from_slice(&data_key))
+    }
+}
+
+fn tree(db: &Db) -> io::Result<Tree> {
+    db.open_tree("data_keys").map_err(io::Error::other)
+}
+
+/// Makes and stores a data key for a new blob, replacing the old one if there was one
+pub fn new_data_key(blob_name: &str, keyring: &Keyring, db: &Db) -> io::Result<Key> {
+    let mut data_key = Key::default();
+    OsRng.fill_bytes(&mut data_key);
+    let wrapped = WrappedKey::wrap(&data_key, blob_name, &keyring.current)?;
+    let encoded = encode_to_vec(wrapped, BINCODE_CONFIG).map_err(io::Error::other)?;
+    tree(db)?
+        .insert(blob_name, encoded)
+        .map_err(io::Error::other)?;
+    Ok(data_key)
+}
+
+pub fn data_key(blob_name: &str, keyring: &Keyring, db: &Db) -> io::Result<Key> {
+    let encoded = tree(db)?
+        .get(blob_name)
+        .map_err(io::Error::other)?
+        .ok_or_else(|| io::Error::other(format!("there's no data key for {}", blob_name)))?;
+    let wrapped: WrappedKey = decode_from_slice(&encoded, BINCODE_CONFIG)
+        .map_err(io::Error::other)?
+        .0;
+    wrapped.unwrap(blob_name, keyring)
+}
+
+pub fn forget_data_key(blob_name: &str, db: &Db) -> io::Result<()> {
+    tree(db)?.remove(blob_name).map_err(io::Error::other)?;
+    Ok(())
+}
+
+/// Wraps data keys that were wrapped with a previous master key with the current one,
+/// returns how many were wrapped again. Once this is done the previous keys can be removed.
+pub fn rewrap_data_keys(keyring: &Keyring, db: &Db) -> Result<usize, Box<dyn Error>> {
+    let tree = tree(db)?;
+    let mut rewrapped = 0;
+    for maybe_pair in tree.iter() {
+        let (name, encoded) = maybe_pair?;
+        let blob_name = String::from_utf8_lossy(&name);
+        let wrapped: WrappedKey = decode_from_slice(&encoded, BINCODE_CONFIG)?.0;
+        if wrapped.master_key_id == keyring.current.id {
+            continue;
+        }
+        let data_key = match wrapped.unwrap(&blob_name, keyring) {
+            Ok(x) => x,
+            Err(e) => {
+                log::error!("Couldn't rewrap data key: {}", e);
+                continue;
+            }
+        };
+        let rewrapped_key = WrappedKey::wrap(&data_key, &blob_name, &keyring.current)?;
+        // only if the blob wasn't written again meanwhile
+        if tree
+            .compare_and_swap(
+                &name,
+                Some(&encoded),
+                Some(IVec::from(encode_to_vec(rewrapped_key, BINCODE_CONFIG)?)),
+            )?
+            .is_ok()
+        {
+            rewrapped += 1;
+        }
+    }
+    Ok(rewrapped)
+}
+
+/// Plaintext length of an encrypted blob that's `encrypted_length` bytes long without its header
+pub fn plaintext_length(encrypted_length: u64) -> u64 {
+    let segment = (SEGMENT_SIZE + TAG_SIZE) as u64;
+    let segments = encrypted_length.div_ceil(segment).max(1);
+    encrypted_length.saturating_sub(segments * TAG_SIZE as u64)
+}
+
+/// Encrypts everything written to it into `W`, including the header.
+/// It has to be shut down, otherwise the last segment is never written.
+pub struct EncryptWriter<W> {
+    inner: W,
+    encryptor: Option<EncryptorBE32<XChaCha20Poly1305>>,
+    plaintext: Vec<u8>,
+    /// Encrypted bytes that still have to be written to `inner`
+    pending: Vec<u8>,
+    written: usize,
+}
+
+impl<W> EncryptWriter<W> {
+    pub fn new(inner: W, data_key: &Key) -> Self {
+        let mut nonce_prefix = [0; NONCE_PREFIX_SIZE];
+        OsRng.fill_bytes(&mut nonce_prefix);
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&nonce_prefix);
+        Self {
+            inner,
+            encryptor: Some(EncryptorBE32::from_aead(
+                XChaCha20Poly1305::new(data_key),
+                (&nonce_prefix).into(),
+            )),
+            plaintext: Vec::with_capacity(SEGMENT_SIZE),
+            pending: header,
+            written: 0,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> EncryptWriter<W> {
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.pending.len() {
+            let written =
+                ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.written..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += written;
+        }
+        self.pending.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn encryption_error() -> io::Error {
+    io::Error::other("couldn't encrypt segment")
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for EncryptWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        let Some(encryptor) = &mut this.encryptor else {
+            return Poll::Ready(Err(io::Error::other("writing after shutdown")));
+        };
+        // a full segment is only sealed once there's more, the last one is sealed differently
+        if this.plaintext.len() == SEGMENT_SIZE && !buf.is_empty() {
+            this.pending = encryptor
+                .encrypt_next(this.plaintext.as_slice())
+                .map_err(|_| encryption_error())?;
+            this.plaintext.clear();
+        }
+        let taken = buf.len().min(SEGMENT_SIZE - this.plaintext.len());
+        this.plaintext.extend_from_slice(&buf[..taken]);
+        Poll::Ready(Ok(taken))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        if let Some(encryptor) = this.encryptor.take() {
+            this.pending = encryptor
+                .encrypt_last(this.plaintext.as_slice())
+                .map_err(|_| encryption_error())?;
+            this.plaintext.clear();
+            ready!(this.poll_pending(cx))?;
+        }
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// Decrypts an encrypted blob whose header was already read from `R`
+pub struct DecryptReader<R> {
+    inner: R,
+    decryptor: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    /// Encrypted bytes after the current segment
+    remaining: u64,
+    segment: Vec<u8>,
+    plaintext: Vec<u8>,
+    read: usize,
+}
+
+impl<R> DecryptReader<R> {
+    /// `encrypted_length` is the length of the blob without its header
+    pub fn new(inner: R, data_key: &Key, header: &[u8], encrypted_length: u64) -> io::Result<Self> {
+        if header.len() != HEADER_SIZE || !header.starts_with(MAGIC) {
+            return Err(io::Error::other("not an encrypted blob"));
+        }
+        Ok(Self {
+            inner,
+            decryptor: Some(DecryptorBE32::from_aead(
+                XChaCha20Poly1305::new(data_key),
+                header[MAGIC.len()..].into(),
+            )),
+            remaining: encrypted_length,
+            segment: Vec::with_capacity(SEGMENT_SIZE + TAG_SIZE),
+            plaintext: vec![],
+            read: 0,
+        })
+    }
+}
+
+fn decryption_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "couldn't decrypt segment, the blob or its key is damaged",
+    )
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for DecryptReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.read == this.plaintext.len() {
+            let Some(decryptor) = &mut this.decryptor else {
+                // done
+                return Poll::Ready(Ok(()));
+            };
+
+            let segment_length = this.remaining.min((SEGMENT_SIZE + TAG_SIZE) as u64) as usize;
+            while this.segment.len() < segment_length {
+                let filled = this.segment.len();
+                this.segment.resize(segment_length, 0);
+                let mut segment_buf = ReadBuf::new(&mut this.segment[filled..]);
+                let result = Pin::new(&mut this.inner).poll_read(cx, &mut segment_buf);
+                let read = segment_buf.filled().len();
+                this.segment.truncate(filled + read);
+                ready!(result)?;
+                if read == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+            }
+
+            this.remaining -= segment_length as u64;
+            this.plaintext = if this.remaining == 0 {
+                let decryptor = this.decryptor.take().unwrap();
+                decryptor.decrypt_last(this.segment.as_slice())
+            } else {
+                decryptor.decrypt_next(this.segment.as_slice())
+            }
+            .map_err(|_| decryption_error())?;
+            this.segment.clear();
+            this.read = 0;
+        }
+
+        let length = buf.remaining().min(this.plaintext.len() - this.read);
+        buf.put_slice(&this.plaintext[this.read..this.read + length]);
+        this.read += length;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    fn master_key(byte: u8) -> String {
+        base64::encode([byte; 32])
+    }
+
+    fn keyring(current: u8, previous: &[u8]) -> Keyring {
+        let config = AppConfig {
+            encryption_key: Some(master_key(current)),
+            previous_encryption_keys: previous.iter().map(|&x| master_key(x)).collect(),
+            ..AppConfig::default()
+        };
+        Keyring::from_config(&config).unwrap().unwrap()
+    }
+
+    fn temporary_db() -> Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    async fn encrypt(plaintext: &[u8], data_key: &Key) -> Vec<u8> {
+        let mut blob = vec![];
+        let mut writer = EncryptWriter::new(&mut blob, data_key);
+        // odd sized writes, so segments get filled over several calls
+        for chunk in plaintext.chunks(1000) {
+            writer.write_all(chunk).await.unwrap();
+        }
+        writer.shutdown().await.unwrap();
+        blob
+    }
+
+    async fn decrypt(blob: &[u8], data_key: &Key) -> io::Result<Vec<u8>> {
+        let (header, encrypted) = blob.split_at(HEADER_SIZE);
+        let mut reader = DecryptReader::new(encrypted, data_key, header, encrypted.len() as u64)?;
+        let mut plaintext = vec![];
+        reader.read_to_end(&mut plaintext).await?;
+        Ok(plaintext)
+    }
+
+    fn plaintext(length: usize) -> Vec<u8> {
+        (0..length).map(|x| (x % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn round_trips_around_segment_boundaries() {
+        let data_key = Key::clone_from_slice(&[7; 32]);
+        for length in [
+            0,
+            1,
+            SEGMENT_SIZE - 1,
+            SEGMENT_SIZE,
+            SEGMENT_SIZE + 1,
+            2 * SEGMENT_SIZE,
+            3 * SEGMENT_SIZE,
+        ] {
+            let plaintext = plaintext(length);
+            let blob = encrypt(&plaintext, &data_key).await;
+            assert!(blob.starts_with(MAGIC));
+            let encrypted_length = (blob.len() - HEADER_SIZE) as u64;
+            assert_eq!(plaintext_length(encrypted_length), length as u64);
+            assert_eq!(decrypt(&blob, &data_key).await.unwrap(), plaintext);
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_blobs_dont_decrypt() {
+        let data_key = Key::clone_from_slice(&[7; 32]);
+        let blob = encrypt(&plaintext(2 * SEGMENT_SIZE), &data_key).await;
+        // cut right after the first segment, which then looks like the last one
+        let first_segment = HEADER_SIZE + SEGMENT_SIZE + TAG_SIZE;
+        assert!(decrypt(&blob[..first_segment], &data_key).await.is_err());
+        assert!(decrypt(&blob[..HEADER_SIZE], &data_key).await.is_err());
+        assert!(decrypt(&blob[..blob.len() - 1], &data_key).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn wrong_data_keys_dont_decrypt() {
+        let blob = encrypt(&plaintext(100), &Key::clone_from_slice(&[7; 32])).await;
+        let wrong_key = Key::clone_from_slice(&[8; 32]);
+        assert!(decrypt(&blob, &wrong_key).await.is_err());
+    }
+
+    #[test]
+    fn data_keys_need_the_right_master_key_and_blob() {
+        let db = temporary_db();
+        let original = new_data_key("a.br", &keyring(1, &[]), &db).unwrap();
+        assert_eq!(data_key("a.br", &keyring(1, &[]), &db).unwrap(), original);
+        assert!(data_key("a.br", &keyring(2, &[]), &db).is_err());
+
+        // the blob name is authenticated
+        let wrapped = tree(&db).unwrap().get("a.br").unwrap().unwrap();
+        tree(&db).unwrap().insert("b.br", wrapped).unwrap();
+        assert!(data_key("b.br", &keyring(1, &[]), &db).is_err());
+    }
+
+    #[test]
+    fn rewraps_under_the_current_key() {
+        let db = temporary_db();
+        let original = new_data_key("a.br", &keyring(1, &[]), &db).unwrap();
+
+        let rotated = keyring(2, &[1]);
+        assert_eq!(data_key("a.br", &rotated, &db).unwrap(), original);
+        assert_eq!(rewrap_data_keys(&rotated, &db).unwrap(), 1);
+        assert_eq!(rewrap_data_keys(&rotated, &db).unwrap(), 0);
+
+        // the previous key isn't needed anymore
+        assert_eq!(data_key("a.br", &keyring(2, &[]), &db).unwrap(), original);
+    }
+
+    #[test]
+    fn plaintext_length_of_partial_segments() {
+        assert_eq!(plaintext_length(0), 0);
+        assert_eq!(plaintext_length(TAG_SIZE as u64), 0);
+        assert_eq!(plaintext_length(TAG_SIZE as u64 + 5), 5);
+        let segment = (SEGMENT_SIZE + TAG_SIZE) as u64;
+        assert_eq!(plaintext_length(segment), SEGMENT_SIZE as u64);
+        assert_eq!(
+            plaintext_length(segment + TAG_SIZE as u64 + 1),
+            SEGMENT_SIZE as u64 + 1
+        );
+    }
+}
@@ -0,0 +1,83 @@
+use std::sync::OnceLock;
+
+use syntect::{
+    easy::HighlightLines,
+    highlighting::{Theme, ThemeSet},
+    html::{styled_line_to_highlighted_html, IncludeBackground},
+    parsing::{SyntaxReference, SyntaxSet},
+    util::LinesWithEndings,
+};
+
+use crate::utils::PASTE_LANGUAGES;
+
+fn syntax_set() -> &'static SyntaxSet {
+    static SYNTAX_SET: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAX_SET.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn theme() -> &'static Theme {
+    static THEME: OnceLock<Theme> = OnceLock::new();
+    THEME.get_or_init(|| {
+        ThemeSet::load_defaults()
+            .themes
+            .remove("InspiredGitHub")
+            .expect("couldn't find default theme")
+    })
+}
+
+/// Detects the language from the file extension first, then the mime type,
+/// then the first line (shebangs and such).
+fn find_syntax<'a>(
+    content: &str,
+    name: &str,
+    mime_type: &str,
+    syntax_set: &'a SyntaxSet,
+) -> &'a SyntaxReference {
+    let from_extension = name
+        .rsplit_once('.')
+        .and_then(|(_, ext)| syntax_set.find_syntax_by_extension(ext));
+    let from_mime = || {
+        PASTE_LANGUAGES
+            .iter()
+            .filter(|(_, _, mime)| *mime == mime_type)
+            .find_map(|(ext, _, _)| syntax_set.find_syntax_by_extension(ext))
+    };
+    let from_first_line = || syntax_set.find_syntax_by_first_line(content);
+
+    from_extension
+        .or_else(from_mime)
+        .or_else(from_first_line)
+        .unwrap_or_else(|| syntax_set.find_syntax_plain_text())
+}
+
+/// Highlights text into one html string per line, the text is escaped.
+pub fn highlight_lines(content: &str, name: &str, mime_type: &str) -> Vec<String> {
+    let syntax_set = syntax_set();
+    let syntax = find_syntax(content, name, mime_type, syntax_set);
+    let mut highlighter = HighlightLines::new(syntax, theme());
+
+    LinesWithEndings::from(content)
+        .map(|line| {
+            let ranges = match highlighter.highlight_line(line, syntax_set) {
+                Ok(x) => x,
+                Err(_) => return escape_line(line),
+            };
+            // the newline is needed to keep track of state, but not in the output
+            let ranges: Vec<_> = ranges
+                .into_iter()
+                .map(|(style, text)| (style, text.trim_end_matches(['\n', '\r'])))
+                .collect();
+            styled_line_to_highlighted_html(&ranges, IncludeBackground::No)
+                .unwrap_or_else(|_| escape_line(line))
+        })
+        .collect()
+}
+
+/// Escapes text into one html string per line, for when it shouldn't be highlighted.
+pub fn plain_lines(content: &str) -> Vec<String> {
+    content.lines().map(escape_line).collect()
+}
+
+fn escape_line(line: &str) -> String {
+    handlebars::html_escape(line.trim_end_matches(['\n', '\r']))
+}
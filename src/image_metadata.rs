@@ -0,0 +1,505 @@
+/*
+Strips EXIF, XMP, IPTC and similar metadata from JPEG, PNG and WebP files by copying only
+the parts of the container that are needed to show the image, so pixels aren't re-encoded.
+Only the EXIF orientation is kept, otherwise phone photos would show up sideways.
+Files whose container can't be parsed are decoded and encoded again as a last resort.
+*/
+
+use std::{error::Error, io::Cursor};
+
+use image::{ImageFormat, ImageReader, Limits};
+
+use crate::AppConfig;
+
+type StripError = Box<dyn Error + Send + Sync>;
+
+/// Mime types metadata can be stripped from
+const STRIPPABLE_MIME_TYPES: &[&str] = &["image/jpeg", "image/png", "image/webp"];
+
+pub fn is_strippable(mime_type: &str) -> bool {
+    STRIPPABLE_MIME_TYPES.contains(&mime_type)
+}
+
+/// Whether to strip an upload, `toggle` is the uploaders choice and the config decides if it's missing
+pub fn should_strip(toggle: Option<&str>, config: &AppConfig) -> Result<bool, String> {
+    match toggle.map(|x| x.trim().to_lowercase()).as_deref() {
+        None | Some("") => Ok(config.strip_image_metadata),
+        Some("true" | "1" | "on" | "yes") => Ok(true),
+        Some("false" | "0" | "off" | "no") => Ok(false),
+        Some(_) => Err("strip_metadata has to be true or false".to_string()),
+    }
+}
+
+/// The image without its metadata, or None if it isn't a type we can strip.
+/// This can fall back to encoding the image again, so call it with spawn_blocking.
+pub fn strip(bytes: &[u8], mime_type: &str) -> Result<Option<Vec<u8>>, StripError> {
+    let (lossless, format) = match mime_type {
+        "image/jpeg" => (strip_jpeg(bytes), ImageFormat::Jpeg),
+        "image/png" => (strip_png(bytes), ImageFormat::Png),
+        "image/webp" => (strip_webp(bytes), ImageFormat::WebP),
+        _ => return Ok(None),
+    };
+    match lossless {
+        Ok(x) => Ok(Some(x)),
+        Err(e) => {
+            log::warn!(
+                "Couldn't strip {} losslessly, encoding it again: {}",
+                mime_type,
+                e
+            );
+            reencode(bytes, format).map(Some)
+        }
+    }
+}
+
+fn reencode(bytes: &[u8], format: ImageFormat) -> Result<Vec<u8>, StripError> {
+    let mut reader = ImageReader::with_format(Cursor::new(bytes), format);
+    let mut limits = Limits::default();
+    limits.max_alloc = Some(512 * 1024 * 1024);
+    reader.limits(limits);
+
+    let mut encoded = Cursor::new(vec![]);
+    reader.decode()?.write_to(&mut encoded, format)?;
+    Ok(encoded.into_inner())
+}
+
+/// Reads the orientation tag from the first IFD of TIFF structured EXIF data
+fn exif_orientation(tiff: &[u8]) -> Option<u16> {
+    let big_endian = match tiff.get(..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let u16_at = |pos: usize| -> Option<u16> {
+        let x: [u8; 2] = tiff.get(pos..pos + 2)?.try_into().ok()?;
+        Some(if big_endian {
+            u16::from_be_bytes(x)
+        } else {
+            u16::from_le_bytes(x)
+        })
+    };
+    let u32_at = |pos: usize| -> Option<u32> {
+        let x: [u8; 4] = tiff.get(pos..pos + 4)?.try_into().ok()?;
+        Some(if big_endian {
+            u32::from_be_bytes(x)
+        } else {
+            u32::from_le_bytes(x)
+        })
+    };
+
+    let ifd = u32_at(4)? as usize;
+    let entries = u16_at(ifd)? as usize;
+    (0..entries)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|&entry| u16_at(entry) == Some(0x0112))
+        // type 3 is SHORT, the value is stored in the entry itself
+        .filter(|&entry| u16_at(entry + 2) == Some(3))
+        .and_then(|entry| u16_at(entry + 8))
+        .filter(|x| (2..=8).contains(x))
+}
+
+/// Minimal big endian TIFF with nothing but the orientation tag
+fn orientation_exif(orientation: u16) -> Vec<u8> {
+    let mut tiff = b"MM\x00\x2a\x00\x00\x00\x08".to_vec();
+    tiff.extend_from_slice(&1u16.to_be_bytes());
+    tiff.extend_from_slice(&[0x01, 0x12, 0x00, 0x03, 0x00, 0x00, 0x00, 0x01]);
+    tiff.extend_from_slice(&orientation.to_be_bytes());
+    tiff.extend_from_slice(&[0x00, 0x00]);
+    // no next IFD
+    tiff.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+    tiff
+}
+
+const EXIF_HEADER: &[u8] = b"Exif\x00\x00";
+
+/// Keeps only the segments needed to decode a JPEG and show it in the right colors.
+/// Anything after the end of the image is dropped too, like the extra images of MPF files.
+fn strip_jpeg(bytes: &[u8]) -> Result<Vec<u8>, StripError> {
+    if !bytes.starts_with(&[0xFF, 0xD8]) {
+        return Err("missing start of image".into());
+    }
+    let mut out = vec![0xFF, 0xD8];
+    // the orientation goes after the JFIF segment if there is one, right after SOI otherwise
+    let mut exif_position = out.len();
+    let mut orientation = None;
+    let mut pos = 2;
+
+    loop {
+        match bytes.get(pos..pos + 2) {
+            Some([0xFF, 0xFF]) => {
+                // fill byte
+                pos += 1;
+                continue;
+            }
+            Some([0xFF, _]) => {}
+            _ => return Err("expected a marker".into()),
+        }
+        let marker = bytes[pos + 1];
+        if marker == 0xD9 {
+            out.extend_from_slice(&[0xFF, 0xD9]);
+            break;
+        }
+        if (0xD0..=0xD7).contains(&marker) || marker == 0x01 {
+            out.extend_from_slice(&bytes[pos..pos + 2]);
+            pos += 2;
+            continue;
+        }
+
+        let length = bytes
+            .get(pos + 2..pos + 4)
+            .map(|x| u16::from_be_bytes([x[0], x[1]]) as usize)
+            .filter(|&x| x >= 2)
+            .ok_or("invalid segment length")?;
+        let end = pos + 2 + length;
+        let segment = bytes.get(pos..end).ok_or("truncated segment")?;
+        let data = &segment[4..];
+
+        let keep = match marker {
+            0xE0 => data.starts_with(b"JFIF\x00"),
+            0xE1 => {
+                if let Some(tiff) = data.strip_prefix(EXIF_HEADER) {
+                    orientation = orientation.or(exif_orientation(tiff));
+                }
+                false
+            }
+            // ICC profiles, but not MPF
+            0xE2 => data.starts_with(b"ICC_PROFILE\x00"),
+            // color transform flags
+            0xEE => data.starts_with(b"Adobe"),
+            // other application segments and comments
+            0xE3..=0xEF | 0xFE => false,
+            _ => true,
+        };
+        if keep {
+            out.extend_from_slice(segment);
+            if marker == 0xE0 && exif_position == 2 {
+                exif_position = out.len();
+            }
+        }
+        pos = end;
+
+        if marker == 0xDA {
+            // entropy coded data runs until the next marker that isn't a restart marker
+            let scan_start = pos;
+            while pos + 1 < bytes.len() {
+                if bytes[pos] == 0xFF
+                    && bytes[pos + 1] != 0x00
+                    && !(0xD0..=0xD7).contains(&bytes[pos + 1])
+                {
+                    break;
+                }
+                pos += 1;
+            }
+            if pos + 1 >= bytes.len() {
+                return Err("truncated scan".into());
+            }
+            out.extend_from_slice(&bytes[scan_start..pos]);
+        }
+    }
+
+    if let Some(orientation) = orientation {
+        let mut segment = vec![0xFF, 0xE1];
+        let tiff = orientation_exif(orientation);
+        segment.extend_from_slice(&((2 + EXIF_HEADER.len() + tiff.len()) as u16).to_be_bytes());
+        segment.extend_from_slice(EXIF_HEADER);
+        segment.extend_from_slice(&tiff);
+        out.splice(exif_position..exif_position, segment);
+    }
+    Ok(out)
+}
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Ancillary PNG chunks that change how the image looks, everything else is dropped
+const PNG_KEPT_CHUNKS: &[&[u8; 4]] = &[
+    b"tRNS", b"gAMA", b"cHRM", b"sRGB", b"iCCP", b"cICP", b"mDCV", b"cLLI", b"sBIT", b"bKGD",
+    b"pHYs", b"hIST", b"sPLT", b"acTL", b"fcTL", b"fdAT",
+];
+
+fn png_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(data);
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(kind);
+    hasher.update(data);
+    chunk.extend_from_slice(&hasher.finalize().to_be_bytes());
+    chunk
+}
+
+/// Keeps the critical PNG chunks and the ancillary ones in `PNG_KEPT_CHUNKS`
+fn strip_png(bytes: &[u8]) -> Result<Vec<u8>, StripError> {
+    if !bytes.starts_with(PNG_SIGNATURE) {
+        return Err("missing png signature".into());
+    }
+    let mut chunks = vec![];
+    let mut orientation = None;
+    let mut pos = PNG_SIGNATURE.len();
+    loop {
+        let header = bytes.get(pos..pos + 8).ok_or("truncated chunk")?;
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let kind: [u8; 4] = header[4..8].try_into().unwrap();
+        let end = pos + 12 + length;
+        let chunk = bytes.get(pos..end).ok_or("truncated chunk")?;
+
+        // critical chunks start with an uppercase letter
+        if kind[0].is_ascii_uppercase() || PNG_KEPT_CHUNKS.contains(&&kind) {
+            chunks.push((kind, chunk));
+        } else if &kind == b"eXIf" {
+            orientation = orientation.or(exif_orientation(&chunk[8..8 + length]));
+        }
+        pos = end;
+        if &kind == b"IEND" {
+            break;
+        }
+    }
+
+    let mut out = PNG_SIGNATURE.to_vec();
+    let mut orientation_chunk = orientation.map(|x| png_chunk(b"eXIf", &orientation_exif(x)));
+    for (kind, chunk) in chunks {
+        // eXIf has to come before the image data
+        if &kind == b"IDAT" {
+            if let Some(x) = orientation_chunk.take() {
+                out.extend_from_slice(&x);
+            }
+        }
+        out.extend_from_slice(chunk);
+    }
+    Ok(out)
+}
+
+/// WebP chunks needed to show the image, EXIF and XMP are dropped along with anything unknown
+const WEBP_KEPT_CHUNKS: &[&[u8; 4]] = &[
+    b"VP8 ", b"VP8L", b"VP8X", b"ALPH", b"ANIM", b"ANMF", b"ICCP",
+];
+
+const VP8X_EXIF_FLAG: u8 = 0x08;
+const VP8X_XMP_FLAG: u8 = 0x04;
+
+fn webp_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = kind.to_vec();
+    chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    chunk.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        chunk.push(0);
+    }
+    chunk
+}
+
+fn strip_webp(bytes: &[u8]) -> Result<Vec<u8>, StripError> {
+    if bytes.len() < 12 || &bytes[..4] != b"RIFF" || &bytes[8..12] != b"WEBP" {
+        return Err("missing webp header".into());
+    }
+    let riff_end = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize + 8;
+    let bytes = bytes.get(..riff_end).ok_or("truncated file")?;
+
+    let mut body = vec![];
+    let mut vp8x_position = None;
+    let mut orientation = None;
+    let mut pos = 12;
+    while pos < riff_end {
+        let header = bytes.get(pos..pos + 8).ok_or("truncated chunk")?;
+        let kind: [u8; 4] = header[..4].try_into().unwrap();
+        let length = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+        let data = bytes
+            .get(pos + 8..pos + 8 + length)
+            .ok_or("truncated chunk")?;
+
+        if WEBP_KEPT_CHUNKS.contains(&&kind) {
+            if &kind == b"VP8X" {
+                // flags, reserved bytes and the canvas size
+                if data.len() < 10 {
+                    return Err("VP8X chunk is too short".into());
+                }
+                vp8x_position = Some(body.len() + 8);
+            }
+            body.extend_from_slice(&webp_chunk(&kind, data));
+        } else if &kind == b"EXIF" {
+            // some writers keep the jpeg style header
+            let tiff = data.strip_prefix(EXIF_HEADER).unwrap_or(data);
+            orientation = orientation.or(exif_orientation(tiff));
+        }
+        pos += 8 + length + length % 2;
+    }
+
+    if let Some(flags) = vp8x_position {
+        body[flags] &= !(VP8X_EXIF_FLAG | VP8X_XMP_FLAG);
+        // EXIF needs the extended format, so it can only be kept if the file already had it
+        if let Some(orientation) = orientation {
+            body[flags] |= VP8X_EXIF_FLAG;
+            body.extend_from_slice(&webp_chunk(b"EXIF", &orientation_exif(orientation)));
+        }
+    }
+
+    let mut out = b"RIFF".to_vec();
+    out.extend_from_slice(&((body.len() + 4) as u32).to_le_bytes());
+    out.extend_from_slice(b"WEBP");
+    out.extend_from_slice(&body);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::{DynamicImage, RgbImage};
+
+    /// Stands in for GPS coordinates and the like, it shouldn't survive stripping
+    const SECRET: &[u8] = b"secret location";
+
+    fn encode(format: ImageFormat) -> Vec<u8> {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 2, [200, 10, 10].into()));
+        let mut encoded = Cursor::new(vec![]);
+        image.write_to(&mut encoded, format).unwrap();
+        encoded.into_inner()
+    }
+
+    fn exif(orientation: u16) -> Vec<u8> {
+        let mut exif = orientation_exif(orientation);
+        exif.extend_from_slice(SECRET);
+        exif
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|x| x == needle)
+    }
+
+    fn position(haystack: &[u8], needle: &[u8]) -> usize {
+        haystack
+            .windows(needle.len())
+            .position(|x| x == needle)
+            .unwrap()
+    }
+
+    fn jpeg_with_metadata() -> Vec<u8> {
+        let jpeg = encode(ImageFormat::Jpeg);
+        let mut app1 = EXIF_HEADER.to_vec();
+        app1.extend_from_slice(&exif(6));
+        let mut segments = vec![0xFF, 0xE1];
+        segments.extend_from_slice(&((app1.len() + 2) as u16).to_be_bytes());
+        segments.extend_from_slice(&app1);
+        segments.extend_from_slice(&[0xFF, 0xFE]);
+        segments.extend_from_slice(&((SECRET.len() + 2) as u16).to_be_bytes());
+        segments.extend_from_slice(SECRET);
+
+        let mut out = jpeg[..2].to_vec();
+        out.extend_from_slice(&segments);
+        out.extend_from_slice(&jpeg[2..]);
+        out
+    }
+
+    fn png_with_metadata() -> Vec<u8> {
+        let png = encode(ImageFormat::Png);
+        // right after IHDR
+        let ihdr_end = PNG_SIGNATURE.len() + 12 + 13;
+        let mut out = png[..ihdr_end].to_vec();
+        out.extend_from_slice(&png_chunk(b"eXIf", &exif(3)));
+        out.extend_from_slice(&png_chunk(b"tEXt", SECRET));
+        out.extend_from_slice(&png[ihdr_end..]);
+        out
+    }
+
+    /// Extended format WebP with the chunks of a simple one, plus EXIF and XMP
+    fn webp_with_metadata() -> Vec<u8> {
+        let simple = encode(ImageFormat::WebP);
+        let mut vp8x = vec![VP8X_EXIF_FLAG | VP8X_XMP_FLAG, 0, 0, 0];
+        vp8x.extend_from_slice(&3u32.to_le_bytes()[..3]);
+        vp8x.extend_from_slice(&1u32.to_le_bytes()[..3]);
+
+        let mut body = webp_chunk(b"VP8X", &vp8x);
+        body.extend_from_slice(&simple[12..]);
+        body.extend_from_slice(&webp_chunk(b"EXIF", &exif(8)));
+        body.extend_from_slice(&webp_chunk(b"XMP ", SECRET));
+        riff(&body)
+    }
+
+    fn riff(body: &[u8]) -> Vec<u8> {
+        let mut out = b"RIFF".to_vec();
+        out.extend_from_slice(&((body.len() + 4) as u32).to_le_bytes());
+        out.extend_from_slice(b"WEBP");
+        out.extend_from_slice(body);
+        out
+    }
+
+    fn assert_decodes(bytes: &[u8], format: ImageFormat) {
+        let image = image::load_from_memory_with_format(bytes, format).unwrap();
+        assert_eq!((image.width(), image.height()), (4, 2));
+    }
+
+    #[test]
+    fn jpeg_keeps_only_the_orientation() {
+        let stripped = strip_jpeg(&jpeg_with_metadata()).unwrap();
+        assert!(!contains(&stripped, SECRET));
+        let tiff = &stripped[position(&stripped, EXIF_HEADER) + EXIF_HEADER.len()..];
+        assert_eq!(exif_orientation(tiff), Some(6));
+        assert_decodes(&stripped, ImageFormat::Jpeg);
+    }
+
+    #[test]
+    fn png_keeps_only_the_orientation() {
+        let stripped = strip_png(&png_with_metadata()).unwrap();
+        assert!(!contains(&stripped, SECRET));
+        let exif = &stripped[position(&stripped, b"eXIf") + 4..];
+        assert_eq!(exif_orientation(exif), Some(3));
+        // eXIf has to come before the image data
+        assert!(position(&stripped, b"eXIf") < position(&stripped, b"IDAT"));
+        assert_decodes(&stripped, ImageFormat::Png);
+    }
+
+    #[test]
+    fn webp_keeps_only_the_orientation() {
+        let stripped = strip_webp(&webp_with_metadata()).unwrap();
+        assert!(!contains(&stripped, SECRET));
+        let flags = stripped[position(&stripped, b"VP8X") + 8];
+        assert_eq!(flags & (VP8X_EXIF_FLAG | VP8X_XMP_FLAG), VP8X_EXIF_FLAG);
+        let exif = &stripped[position(&stripped, b"EXIF") + 8..];
+        assert_eq!(exif_orientation(exif), Some(8));
+        assert_decodes(&stripped, ImageFormat::WebP);
+    }
+
+    #[test]
+    fn images_without_metadata_stay_the_same() {
+        let png = encode(ImageFormat::Png);
+        assert_eq!(strip_png(&png).unwrap(), png);
+        let webp = encode(ImageFormat::WebP);
+        assert_eq!(strip_webp(&webp).unwrap(), webp);
+    }
+
+    #[test]
+    fn truncated_images_are_errors() {
+        let images = [
+            (
+                webp_with_metadata(),
+                strip_webp as fn(&[u8]) -> Result<Vec<u8>, StripError>,
+            ),
+            (jpeg_with_metadata(), strip_jpeg),
+            (png_with_metadata(), strip_png),
+        ];
+        for (bytes, strip) in images {
+            for length in 0..bytes.len() - 1 {
+                assert!(strip(&bytes[..length]).is_err(), "length {}", length);
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_images_are_errors() {
+        assert!(strip_jpeg(b"not a jpeg").is_err());
+        assert!(strip_png(b"not a png").is_err());
+        assert!(strip_webp(b"RIFF\x04\x00\x00\x00WEBX").is_err());
+        // a jpeg segment length has to include itself
+        assert!(strip_jpeg(&[0xFF, 0xD8, 0xFF, 0xE1, 0x00, 0x01]).is_err());
+
+        // strip falls back to decoding, which fails too
+        assert!(strip(b"\xFF\xD8garbage", "image/jpeg").is_err());
+    }
+
+    #[test]
+    fn short_vp8x_chunks_are_errors() {
+        for length in 0..10 {
+            let mut body = webp_chunk(b"VP8X", &vec![0xFF; length]);
+            body.extend_from_slice(&webp_chunk(b"VP8L", &[0; 8]));
+            assert!(strip_webp(&riff(&body)).is_err(), "length {}", length);
+        }
+    }
+}
@@ -2,24 +2,45 @@ use std::{
     fs,
     net::SocketAddr,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use api::get_api_router;
-use axum::{response::Redirect, routing::get, Router};
+use axum::{middleware, response::Redirect, routing::get, Router};
+use encryption::Keyring;
 use figment::{
     providers::{Env, Format, Serialized, Toml},
     Figment,
 };
+use ipnet::IpNet;
 use owo_colors::OwoColorize;
 use pages::get_pages_router;
+use regex::Regex;
+use scanner::{Clamd, Scanner};
 use serde::{Deserialize, Serialize};
 use sled::Db;
 use static_files::static_handler;
+use upload_rules::UploadRules;
 
+mod admin;
 mod api;
+mod blocklist;
+mod client_ip;
 pub mod dbman;
+mod encryption;
+mod highlight;
+mod image_metadata;
+mod markdown;
 mod pages;
+mod password;
+mod ratelimit;
+mod reports;
+mod scanner;
+mod security;
+mod sniff;
 mod static_files;
+mod thumbnail;
+mod upload_rules;
 pub mod utils;
 
 #[cfg(debug_assertions)]
@@ -58,30 +79,214 @@ fn setup_logger() -> Result<(), fern::InitError> {
     Ok(())
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeader {
+    /// `X-Forwarded-For: client, proxy1`, what nginx's `$proxy_add_x_forwarded_for` sends
+    XForwardedFor,
+    /// `X-Real-IP: client`
+    XRealIp,
+    /// `Forwarded: for=client, for=proxy1`, from RFC 7239
+    Forwarded,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdStyle {
+    /// Letters and digits, like `x7Kq2mPz9A`
+    Random,
+    /// Dash separated words, like `amber-river-falcon-maple`
+    Words,
+}
+
+/// What happens to uploads that are infected, or couldn't be scanned
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanFailureAction {
+    /// Refuse the upload
+    Reject,
+    /// Store it, but don't let anyone download it
+    Quarantine,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct TypeSizeLimit {
+    /// Mime types the limit applies to, like `^video/`
+    mime_regex: String,
+    limit: byte_unit::Byte,
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct AppConfig {
     file_size_limit: byte_unit::Byte,
+    /// Seconds until uploaded files are deleted, they're kept forever if this isn't set
+    file_expiry_length: Option<u64>,
+    /// Size limit of pastes, larger text files won't be shown as pastes either
+    paste_size_limit: byte_unit::Byte,
     /// Length of period in seconds
     ratelimit_period_length: u64,
     /// Byte limit you can upload every ratelimit_period_length seconds.
     ratelimit_period_byte_limit: byte_unit::Byte,
+    /// IPv6 addresses share a ratelimit with their whole prefix of this length,
+    /// since one client usually gets at least a /64
+    ratelimit_ipv6_prefix: u8,
+    /// Same for IPv4, 32 means every address is limited on its own, 24 groups them by /24
+    ratelimit_ipv4_prefix: u8,
+    /// Length of the download ratelimit period in seconds
+    download_ratelimit_period_length: u64,
+    /// Downloads a client can start every download_ratelimit_period_length seconds
+    download_ratelimit_requests: u64,
+    /// Bytes a client can download every download_ratelimit_period_length seconds,
+    /// downloads are slowed down rather than stopped once that's used up
+    download_ratelimit_byte_limit: byte_unit::Byte,
+    /// Cap on the bytes per second sent to all clients together
+    egress_limit: Option<byte_unit::Byte>,
     allowed_preview_mime_regex: String,
+    /// Only files with these extensions can be uploaded, any extension if it's empty
+    allowed_extensions: Vec<String>,
+    /// Files with these extensions can't be uploaded, like `["exe", "msi", "iso"]`
+    denied_extensions: Vec<String>,
+    /// Only files with a mime type matching this can be uploaded
+    allowed_upload_mime_regex: Option<String>,
+    /// Files with a mime type matching this can't be uploaded
+    denied_upload_mime_regex: Option<String>,
+    /// Smaller size limits for some mime types
+    type_size_limits: Vec<TypeSizeLimit>,
+    /// clamd socket to scan uploads with, a path like /run/clamav/clamd.ctl or an address like 127.0.0.1:3310
+    clamd_address: Option<String>,
+    scan_failure_action: ScanFailureAction,
+    /// Remove EXIF, XMP and IPTC metadata from JPEG, PNG and WebP uploads,
+    /// uploaders can still choose with a strip_metadata field
+    strip_image_metadata: bool,
+    /// Text files larger than this are shown without syntax highlighting
+    highlight_size_limit: byte_unit::Byte,
+    /// Images larger than this won't get a thumbnail
+    thumbnail_source_size_limit: byte_unit::Byte,
+    /// Max width and height of thumbnails in pixels
+    thumbnail_size: u32,
     db_path: PathBuf,
+    /// Encrypts blobs at rest when set, 32 random bytes in base64 like `openssl rand -base64 32` makes
+    encryption_key: Option<String>,
+    /// File with the encryption key in it, instead of putting it in the config
+    encryption_key_file: Option<PathBuf>,
+    /// Keys that were used before the current one, data keys wrapped with them are
+    /// wrapped with the current key on startup
+    previous_encryption_keys: Vec<String>,
     sled_cache_cap: byte_unit::Byte,
     port: u16,
+    /// How ids of new files look, random characters or words
+    id_style: IdStyle,
+    /// Number of characters in random ids
+    id_length: usize,
+    /// Number of words in word based ids, there are 512 to pick from
+    id_words: usize,
+    /// Bearer tokens that let uploaders pick a custom slug instead of a random id
+    upload_tokens: Vec<String>,
+    /// Custom slugs have to match this
+    slug_regex: String,
+    /// Bearer tokens for the admin api
+    admin_tokens: Vec<String>,
+    /// Hash list imported into the blocklist on startup, one sha256 per line like sha256sum's output
+    blocklist_file: Option<PathBuf>,
+    /// Length of the report ratelimit period in seconds
+    report_ratelimit_period_length: u64,
+    /// Files a client can report every report_ratelimit_period_length seconds
+    report_ratelimit_requests: u64,
+    /// Length of the password ratelimit period in seconds
+    password_ratelimit_period_length: u64,
+    /// Passwords a client can try every password_ratelimit_period_length seconds
+    password_ratelimit_requests: u64,
+    /// Files reported by this many different clients are hidden until a moderator looks at them
+    report_hide_threshold: Option<usize>,
+    /// Reverse proxies whose forwarded headers are believed, like `["127.0.0.1/32", "10.0.0.0/8"]`.
+    /// Requests from anywhere else are attributed to the connecting address.
+    trusted_proxies: Vec<IpNet>,
+    /// The header the trusted proxies put the client address in, the others are ignored
+    /// since proxies usually pass them on from the client untouched
+    forwarded_header: ForwardedHeader,
+    /// Base url used in absolute links, like https://files.example.com.
+    /// The Host header is used when this isn't set.
+    public_url: Option<String>,
+    /// Separate domain that previews are served from, like https://files-content.example.com.
+    /// It should point to this same instance. Needs `public_url` so previews can be embedded.
+    user_content_url: Option<String>,
+}
+
+/// Shorter ids run out or collide too quickly, 62^6 and 512^3 leave plenty of room
+const MIN_ID_LENGTH: usize = 6;
+const MIN_ID_WORDS: usize = 3;
+
+impl AppConfig {
+    /// Catches settings that would only break things later, like when the first upload comes in
+    fn validate(&self) -> Result<(), String> {
+        match self.id_style {
+            IdStyle::Random if self.id_length < MIN_ID_LENGTH => {
+                return Err(format!("id_length has to be at least {}", MIN_ID_LENGTH))
+            }
+            IdStyle::Words if self.id_words < MIN_ID_WORDS => {
+                return Err(format!("id_words has to be at least {}", MIN_ID_WORDS))
+            }
+            _ => {}
+        }
+        if self.ratelimit_ipv4_prefix > 32 {
+            return Err("ratelimit_ipv4_prefix can't be larger than 32".to_string());
+        }
+        if self.ratelimit_ipv6_prefix > 128 {
+            return Err("ratelimit_ipv6_prefix can't be larger than 128".to_string());
+        }
+        Ok(())
+    }
 }
 
 impl Default for AppConfig {
     fn default() -> Self {
         AppConfig {
             file_size_limit: byte_unit::Byte::from_str("1 GiB").unwrap(),
+            file_expiry_length: None,
+            paste_size_limit: byte_unit::Byte::from_str("1 MiB").unwrap(),
             ratelimit_period_length: 60 * 60 * 24, // One day
             ratelimit_period_byte_limit: byte_unit::Byte::from_str("2 GiB").unwrap(),
+            ratelimit_ipv6_prefix: 64,
+            ratelimit_ipv4_prefix: 32,
+            download_ratelimit_period_length: 60 * 60, // One hour
+            download_ratelimit_requests: 1000,
+            download_ratelimit_byte_limit: byte_unit::Byte::from_str("20 GiB").unwrap(),
+            egress_limit: None,
             allowed_preview_mime_regex:
                 r"^((audio|image|video)/[a-z.+-]+|(application/json|text/plain))$".to_string(),
+            allowed_extensions: vec![],
+            denied_extensions: vec![],
+            allowed_upload_mime_regex: None,
+            denied_upload_mime_regex: None,
+            type_size_limits: vec![],
+            clamd_address: None,
+            scan_failure_action: ScanFailureAction::Reject,
+            strip_image_metadata: false,
+            highlight_size_limit: byte_unit::Byte::from_str("256 KiB").unwrap(),
+            thumbnail_source_size_limit: byte_unit::Byte::from_str("20 MiB").unwrap(),
+            thumbnail_size: 512,
             db_path: Path::new("./filebin_db").to_path_buf(),
+            encryption_key: None,
+            encryption_key_file: None,
+            previous_encryption_keys: vec![],
             sled_cache_cap: byte_unit::Byte::from_str("0.5 GiB").unwrap(),
             port: 8080,
+            id_style: IdStyle::Random,
+            id_length: 10,
+            id_words: 4,
+            upload_tokens: vec![],
+            admin_tokens: vec![],
+            blocklist_file: None,
+            report_ratelimit_period_length: 60 * 60, // One hour
+            report_ratelimit_requests: 10,
+            password_ratelimit_period_length: 60 * 15, // 15 minutes
+            password_ratelimit_requests: 10,
+            report_hide_threshold: None,
+            slug_regex: r"^[a-z0-9][a-z0-9-]{1,62}[a-z0-9]$".to_string(),
+            trusted_proxies: vec![],
+            forwarded_header: ForwardedHeader::XForwardedFor,
+            public_url: None,
+            user_content_url: None,
         }
     }
 }
@@ -97,6 +302,13 @@ pub struct AppState {
     db: Db,
     config: AppConfig,
     priv_config: PrivAppConfig,
+    scanner: Option<Arc<dyn Scanner>>,
+    /// Only there if encryption at rest is turned on
+    keyring: Option<Arc<Keyring>>,
+    upload_rules: Arc<UploadRules>,
+    slug_regex: Regex,
+    /// Signs the cookies that let browsers into password protected files
+    access_key: [u8; 32],
 }
 
 // TODO: graceful shutdown?
@@ -111,6 +323,7 @@ async fn main() {
         .merge(Toml::file("filebin.toml"));
 
     let config: AppConfig = figment.extract().expect("Couldn't initialize config");
+    config.validate().expect("Invalid config");
     let priv_config = PrivAppConfig {
         sled_path: config.db_path.join("sled"),
         blob_path: config.db_path.join("blob"),
@@ -127,12 +340,61 @@ async fn main() {
         .open()
         .expect("Couldn't open database");
 
+    let access_key = password::load_access_key(&db).expect("Couldn't load access cookie key");
+
     let app_state = AppState {
         db,
         config: config.clone(),
         priv_config,
+        scanner: config
+            .clamd_address
+            .as_deref()
+            .map(|x| Arc::new(Clamd::new(x)) as Arc<dyn Scanner>),
+        keyring: Keyring::from_config(&config)
+            .expect("Invalid encryption key")
+            .map(Arc::new),
+        upload_rules: Arc::new(
+            UploadRules::from_config(&config).expect("Invalid regex in the upload rules"),
+        ),
+        slug_regex: Regex::new(&config.slug_regex).expect("Invalid slug_regex"),
+        access_key,
     };
 
+    if let Some(keyring) = &app_state.keyring {
+        let rewrapped = encryption::rewrap_data_keys(keyring, &app_state.db)
+            .expect("Couldn't rewrap data keys");
+        if rewrapped > 0 {
+            log::info!(
+                "Wrapped {} data keys with the new encryption key",
+                rewrapped
+            );
+        }
+    }
+
+    let upgraded = dbman::upgrade_legacy_records(&app_state)
+        .await
+        .expect("Couldn't upgrade legacy metadata");
+    if upgraded > 0 {
+        log::info!(
+            "Upgraded metadata of {} files from an older version",
+            upgraded
+        );
+    }
+
+    if let Some(blocklist_file) = &config.blocklist_file {
+        let hash_list = fs::read_to_string(blocklist_file).expect("Couldn't read blocklist file");
+        let (added, invalid) =
+            blocklist::import(&hash_list, Some("imported".to_string()), &app_state.db)
+                .expect("Couldn't import blocklist file");
+        log::info!("Imported {} hashes from the blocklist file", added);
+        for line in invalid {
+            log::warn!("Invalid line in blocklist file: {}", line);
+        }
+    }
+
+    tokio::spawn(dbman::expiry_sweeper(app_state.clone()));
+    tokio::spawn(ratelimit::bucket_sweeper(app_state.clone()));
+
     log::info!("Building router...");
 
     // build our application with a single route
@@ -144,6 +406,7 @@ async fn main() {
             get(|| async { Redirect::permanent("/favicon.svg") }),
         )
         .fallback(static_handler)
+        .layer(middleware::from_fn(security::security_headers))
         .with_state(app_state);
 
     let address = format!("0.0.0.0:{}", config.port);
@@ -0,0 +1,28 @@
+use ammonia::UrlRelative;
+use pulldown_cmark::{html, Event, Options, Parser};
+
+pub fn is_markdown(name: &str, mime_type: &str) -> bool {
+    let extension = name.rsplit_once('.').map(|(_, ext)| ext.to_lowercase());
+    mime_type == "text/markdown" || matches!(extension.as_deref(), Some("md" | "markdown"))
+}
+
+/// Renders markdown to sanitised html. Raw html in the markdown is shown as text
+/// and relative links are removed, since they would point somewhere in filebin.
+pub fn render(content: &str) -> String {
+    let parser = Parser::new_ext(
+        content,
+        Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS,
+    )
+    .map(|event| match event {
+        Event::Html(x) | Event::InlineHtml(x) => Event::Text(x),
+        x => x,
+    });
+
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, parser);
+
+    ammonia::Builder::default()
+        .url_relative(UrlRelative::Deny)
+        .clean(&unsafe_html)
+        .to_string()
+}
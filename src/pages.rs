@@ -1,47 +1,157 @@
-use std::error::Error;
+use std::{collections::HashMap, error::Error};
 
 use axum::{
-    body::boxed,
-    extract::{Path, State},
-    http::header,
-    response::Response,
-    routing::get,
+    body::{boxed, Full},
+    extract::{Form, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
     Router,
 };
 use chrono::Duration;
 use handlebars::Handlebars;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 use rust_embed::RustEmbed;
+use serde::Deserialize;
 use serde_json::json;
 
 use crate::{
-    dbman,
+    api,
+    client_ip::ClientIp,
+    dbman::{self, FileInfo},
+    highlight, markdown,
+    password::{self, FileAccess},
+    ratelimit, security, thumbnail,
     utils::{self, should_preview},
-    AppState,
+    AppConfig, AppState,
 };
 
 #[derive(RustEmbed)]
 #[folder = "pages/"]
 struct Assets;
 
-fn render_file(filename: &str, json: &serde_json::Value) -> Result<String, Box<dyn Error>> {
-    let reg = Handlebars::new();
+fn read_asset(filename: &str) -> Result<String, Box<dyn Error>> {
     let file_contents: rust_embed::EmbeddedFile = match Assets::get(filename) {
         Some(x) => Ok(x),
         None => Err("Couldn't find file: ".to_string() + filename),
     }?;
-    let test = file_contents.data.to_vec();
-    let file_str = std::str::from_utf8(&test)?;
-    Ok(reg.render_template(file_str, json)?)
+    Ok(String::from_utf8(file_contents.data.to_vec())?)
+}
+
+/// Renders a page, everything in pages/partials/ can be used as a partial like `{{> meta}}`.
+/// Inline scripts need `nonce="{{ nonce }}"`, the nonce is returned for the page's CSP header.
+fn render_file(
+    filename: &str,
+    json: &serde_json::Value,
+) -> Result<(String, String), Box<dyn Error>> {
+    let nonce = security::nonce();
+    let mut json = json.clone();
+    json["nonce"] = json!(nonce);
+
+    let mut reg = Handlebars::new();
+    for partial in Assets::iter().filter(|x| x.starts_with("partials/")) {
+        let name = partial
+            .trim_start_matches("partials/")
+            .trim_end_matches(".hbs");
+        reg.register_partial(name, read_asset(&partial)?)?;
+    }
+    Ok((reg.render_template(&read_asset(filename)?, &json)?, nonce))
+}
+
+/// OpenGraph and twitter card metadata for link unfurling, rendered by partials/meta.hbs
+fn link_metadata(
+    info: &FileInfo,
+    page_url: &str,
+    base_url: &str,
+    content_link: &str,
+    state: &AppState,
+) -> serde_json::Value {
+    let image = thumbnail::has_thumbnail(info, &state.config)
+        .then(|| format!("{}{}", base_url, utils::get_thumbnail_link(info.id.clone())));
+    let top_level_type = info
+        .detected_mime_type
+        .split('/')
+        .next()
+        .unwrap_or_default();
+
+    json!({
+        "title": info.name,
+        "type": match top_level_type {
+            "video" => "video.other",
+            "audio" => "music.song",
+            _ => "website",
+        },
+        "url": page_url,
+        "description": match &info.description {
+            Some(x) => x.clone(),
+            None => format!("{}, {}", utils::readable_size(info.size as u128), info.mime_type),
+        },
+        "mimeType": info.mime_type,
+        "image": image,
+        "video": (top_level_type == "video").then_some(content_link),
+        "audio": (top_level_type == "audio").then_some(content_link),
+        "twitterCard": if image.is_some() { "summary_large_image" } else { "summary" },
+        "oembed": format!(
+            "{}/api/oembed?url={}",
+            base_url,
+            utf8_percent_encode(page_url, NON_ALPHANUMERIC)
+        ),
+    })
+}
+
+/// Shell-quotes a string for the command snippets on the file page
+fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', r"'\''"))
+}
+
+/// Human readable details about a file, shown on the file page
+fn file_details(info: &FileInfo, content_link: &str, state: &AppState) -> serde_json::Value {
+    let timeago = timeago::Formatter::new();
+    let now = chrono::Utc::now();
+
+    json!({
+        "size": utils::readable_size(info.size as u128),
+        "mimeType": info.mime_type,
+        "detectedMimeType": (info.detected_mime_type != info.mime_type)
+            .then_some(&info.detected_mime_type),
+        "uploaded": timeago.convert((now - info.upload_date).to_std().unwrap_or_default()),
+        "uploadDate": info.upload_date.to_rfc2822(),
+        "expires": info.expiry_date.map(|x| {
+            timeago
+                .convert((x - now).to_std().unwrap_or_default())
+                .replace(" ago", "")
+        }),
+        "expiryDate": info.expiry_date.map(|x| x.to_rfc2822()),
+        "hash": info.hash,
+        "description": info.description,
+        "downloads": dbman::read_download_count(&info.id, &state.db),
+        "revision": info.revision,
+        "oldRevisions": (0..info.revision)
+            .rev()
+            .map(|x| json!({
+                "revision": x,
+                "link": format!("{}?rev={}", utils::get_download_link(info.id.clone()), x),
+            }))
+            .collect::<Vec<_>>(),
+        "directLink": content_link,
+        "curl": format!("curl -o {} {}", shell_quote(&info.name), shell_quote(content_link)),
+        "wget": format!("wget -O {} {}", shell_quote(&info.name), shell_quote(content_link)),
+    })
 }
 
 async fn upload(State(state): State<AppState>) -> Response {
     let timeago = timeago::Formatter::new();
 
-    let body = render_file(
+    let (body, nonce) = render_file(
         "upload.hbs",
         &json!({
-            "maxFilesize": (&state.config.file_size_limit).get_bytes() as u64,
+            "maxFilesize": state.config.file_size_limit.get_bytes() as u64,
             "maxFilesizeReadable": state.config.file_size_limit.get_appropriate_unit(true).to_string().replace(".00", ""),
+            "stripImageMetadata": state.config.strip_image_metadata,
+            "languages": utils::PASTE_LANGUAGES
+                .iter()
+                .map(|(ext, name, _)| json!({ "ext": ext, "name": name }))
+                .collect::<Vec<_>>(),
             "maxUploadPerPeriodText": format!(
                 "Upload limit is {} per {}",
                 state.config.ratelimit_period_byte_limit.get_appropriate_unit(true).to_string().replace(".00", ""),
@@ -54,11 +164,73 @@ async fn upload(State(state): State<AppState>) -> Response {
 
     Response::builder()
         .header(header::CONTENT_TYPE, "text/html")
+        .header(
+            header::CONTENT_SECURITY_POLICY,
+            security::page_csp(&nonce, &state.config),
+        )
         .body(boxed(body)) // I have no idea why this needs to be boxed but whatever
         .unwrap()
 }
 
-async fn file(Path(file): Path<String>, State(state): State<AppState>) -> Response {
+fn is_shown_as_text(info: &FileInfo, state: &AppState) -> bool {
+    info.detected_mime_type == "text/plain"
+        && utils::is_text_mime(&info.mime_type)
+        && info.size as u128 <= state.config.paste_size_limit.get_bytes()
+}
+
+/// Lines of a text file as html, syntax highlighted unless the file is too large.
+async fn text_preview_lines(
+    info: &FileInfo,
+    content: &str,
+    state: &AppState,
+) -> Vec<serde_json::Value> {
+    let html_lines = if info.size as u128 > state.config.highlight_size_limit.get_bytes() {
+        highlight::plain_lines(content)
+    } else if let Some(cached) = dbman::read_highlight_cache(&info.id, state).await {
+        cached
+    } else {
+        // highlighting a big file takes a while, so it's kept off the async threads
+        let (text, name, mime_type) = (
+            content.to_string(),
+            info.name.clone(),
+            info.mime_type.clone(),
+        );
+        let highlighted = match tokio::task::spawn_blocking(move || {
+            highlight::highlight_lines(&text, &name, &mime_type)
+        })
+        .await
+        {
+            Ok(x) => x,
+            Err(e) => {
+                log::error!("Couldn't highlight {}: {}", info.id, e);
+                // not cached, maybe it works next time
+                return numbered_lines(highlight::plain_lines(content));
+            }
+        };
+        if let Err(e) = dbman::write_highlight_cache(&info.id, &highlighted, state).await {
+            log::error!("Couldn't write highlight cache of {}: {}", info.id, e);
+        }
+        highlighted
+    };
+
+    numbered_lines(html_lines)
+}
+
+fn numbered_lines(html_lines: Vec<String>) -> Vec<serde_json::Value> {
+    html_lines
+        .into_iter()
+        .enumerate()
+        .map(|(i, html)| json!({ "number": i + 1, "html": html }))
+        .collect()
+}
+
+async fn file(
+    Path(file): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    State(state): State<AppState>,
+    ClientIp(ip_address): ClientIp,
+    headers: HeaderMap,
+) -> Response {
     let uid = file;
     let maybe_info = dbman::read_file_info(uid.clone(), &state.db);
     if maybe_info.is_none() {
@@ -69,21 +241,288 @@ async fn file(Path(file): Path<String>, State(state): State<AppState>) -> Respon
     }
     let info = maybe_info.unwrap();
 
-    let should_preview = should_preview(&info.mime_type, &state.config);
+    let ratelimit_token = ratelimit::ip_ratelimit_token(ip_address, &state.config);
+    match password::file_access(&info, &headers, &ratelimit_token, &state).await {
+        FileAccess::Allowed => {}
+        FileAccess::Denied => {
+            return password_page(&info, false, &format!("/file/{}", info.id), &state.config)
+        }
+        FileAccess::Ratelimited(retry_after) => return password::too_many_attempts(retry_after),
+    }
+    if info.is_withheld() {
+        return api::withheld(&info);
+    }
+
+    let should_preview = should_preview(&info, &state.config);
+    let base_url = utils::base_url(&state.config, &headers);
+    let content_link = utils::content_link(&info, &state.config, &headers);
 
-    let body = render_file(
+    let is_markdown = markdown::is_markdown(&info.name, &info.mime_type);
+    // markdown is rendered unless the raw view is asked for
+    let show_rendered = is_markdown && params.get("view").map(String::as_str) != Some("raw");
+
+    let mut lines = None;
+    let mut rendered = None;
+    if is_shown_as_text(&info, &state) {
+        let bytes = dbman::read_file_bytes(&info, &state)
+            .await
+            .expect("couldn't read text file");
+        let content = String::from_utf8_lossy(&bytes);
+        if show_rendered {
+            rendered = Some(markdown::render(&content));
+        } else {
+            lines = Some(text_preview_lines(&info, &content, &state).await);
+        }
+    }
+
+    let (body, nonce) = render_file(
         "file.hbs",
         &json!({
             "id": info.id,
             "filename": info.name,
-            "img": utils::get_download_link(uid),
+            "img": content_link,
+            "thumb": thumbnail::has_thumbnail(&info, &state.config).then(|| utils::get_thumbnail_link(uid)),
             "shouldPreview": should_preview,
+            "lines": lines,
+            "isMarkdown": is_markdown && is_shown_as_text(&info, &state),
+            "rendered": rendered,
+            "meta": link_metadata(&info, &format!("{}/file/{}", base_url, info.id), &base_url, &content_link, &state),
+            "details": file_details(&info, &content_link, &state),
+        }),
+    )
+    .expect("rendering failed");
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/html")
+        .header(
+            header::CONTENT_SECURITY_POLICY,
+            security::page_csp(&nonce, &state.config),
+        )
+        .body(boxed(body)) // I have no idea why this needs to be boxed but whatever
+        .unwrap()
+}
+
+async fn paste(
+    Path(file): Path<String>,
+    State(state): State<AppState>,
+    ClientIp(ip_address): ClientIp,
+    headers: HeaderMap,
+) -> Response {
+    let uid = file;
+    let maybe_info = dbman::read_file_info(uid.clone(), &state.db);
+    if maybe_info.is_none() {
+        return Response::builder()
+            .status(404)
+            .body(boxed("404".to_string())) // I have no idea why this needs to be boxed but whatever
+            .unwrap();
+    }
+    let info = maybe_info.unwrap();
+
+    let ratelimit_token = ratelimit::ip_ratelimit_token(ip_address, &state.config);
+    match password::file_access(&info, &headers, &ratelimit_token, &state).await {
+        FileAccess::Allowed => {}
+        FileAccess::Denied => {
+            return password_page(&info, false, &format!("/p/{}", info.id), &state.config)
+        }
+        FileAccess::Ratelimited(retry_after) => return password::too_many_attempts(retry_after),
+    }
+    if info.is_withheld() {
+        return api::withheld(&info);
+    }
+
+    if !is_shown_as_text(&info, &state) {
+        return Redirect::temporary(&format!("/file/{}", uid)).into_response();
+    }
+
+    let bytes = dbman::read_file_bytes(&info, &state)
+        .await
+        .expect("couldn't read paste");
+    let content = String::from_utf8_lossy(&bytes);
+
+    let lines = text_preview_lines(&info, &content, &state).await;
+    let base_url = utils::base_url(&state.config, &headers);
+    let content_link = utils::content_link(&info, &state.config, &headers);
+
+    let (body, nonce) = render_file(
+        "paste.hbs",
+        &json!({
+            "id": info.id,
+            "filename": info.name,
+            "content": content,
+            "lines": lines,
+            "raw": format!("/p/{}/raw", uid),
+            "meta": link_metadata(&info, &format!("{}/p/{}", base_url, info.id), &base_url, &content_link, &state),
+            "img": content_link,
+        }),
+    )
+    .expect("rendering failed");
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/html")
+        .header(
+            header::CONTENT_SECURITY_POLICY,
+            security::page_csp(&nonce, &state.config),
+        )
+        .body(boxed(body)) // I have no idea why this needs to be boxed but whatever
+        .unwrap()
+}
+
+async fn paste_raw(
+    Path(file): Path<String>,
+    State(state): State<AppState>,
+    ClientIp(ip_address): ClientIp,
+    headers: HeaderMap,
+) -> Response {
+    let uid = file;
+    let maybe_info = dbman::read_file_info(uid.clone(), &state.db);
+    if maybe_info.is_none() {
+        return Response::builder()
+            .status(404)
+            .body(boxed("404".to_string())) // I have no idea why this needs to be boxed but whatever
+            .unwrap();
+    }
+    let info = maybe_info.unwrap();
+
+    let ratelimit_token = ratelimit::ip_ratelimit_token(ip_address, &state.config);
+    match password::file_access(&info, &headers, &ratelimit_token, &state).await {
+        FileAccess::Allowed => {}
+        FileAccess::Denied => {
+            return password_page(&info, false, &format!("/p/{}/raw", info.id), &state.config)
+        }
+        FileAccess::Ratelimited(retry_after) => return password::too_many_attempts(retry_after),
+    }
+    if info.is_withheld() {
+        return api::withheld(&info);
+    }
+
+    if !is_shown_as_text(&info, &state) {
+        return Redirect::temporary(&utils::get_download_link(uid)).into_response();
+    }
+
+    let bytes = dbman::read_file_bytes(&info, &state)
+        .await
+        .expect("couldn't read paste");
+
+    // always plain text, so pasted html or scripts are never rendered
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .header(
+            header::CONTENT_SECURITY_POLICY,
+            security::user_content_csp(&state.config),
+        )
+        .body(boxed(Full::from(bytes)))
+        .unwrap()
+}
+
+/// Asks for the password of a protected file, `return_to` is where to go after
+fn password_page(
+    info: &FileInfo,
+    wrong_password: bool,
+    return_to: &str,
+    config: &AppConfig,
+) -> Response {
+    let (body, nonce) = render_file(
+        "password.hbs",
+        &json!({
+            "id": info.id,
+            "next": return_to,
+            "wrongPassword": wrong_password,
+        }),
+    )
+    .expect("rendering failed");
+
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(header::CONTENT_TYPE, "text/html")
+        .header(
+            header::CONTENT_SECURITY_POLICY,
+            security::page_csp(&nonce, config),
+        )
+        .body(boxed(body)) // I have no idea why this needs to be boxed but whatever
+        .unwrap()
+}
+
+#[derive(Deserialize)]
+struct PasswordForm {
+    password: String,
+    next: Option<String>,
+}
+
+/// Checks the password from the password page and hands out an access cookie for the file
+async fn unlock(
+    Path(file): Path<String>,
+    State(state): State<AppState>,
+    ClientIp(ip_address): ClientIp,
+    Form(form): Form<PasswordForm>,
+) -> Response {
+    let maybe_info = dbman::read_file_info(file, &state.db);
+    if maybe_info.is_none() {
+        return Response::builder()
+            .status(404)
+            .body(boxed("404".to_string())) // I have no idea why this needs to be boxed but whatever
+            .unwrap();
+    }
+    let info = maybe_info.unwrap();
+
+    // only ever back to a page of this file, so this can't be used as an open redirect
+    let pages = [
+        format!("/file/{}", info.id),
+        format!("/p/{}", info.id),
+        format!("/p/{}/raw", info.id),
+    ];
+    let return_to = match form.next {
+        Some(x) if pages.contains(&x) => x,
+        _ => pages[0].clone(),
+    };
+
+    let ratelimit_token = ratelimit::ip_ratelimit_token(ip_address, &state.config);
+    match password::check_password(&info, &form.password, &ratelimit_token, &state).await {
+        Ok(true) => Response::builder()
+            .status(StatusCode::SEE_OTHER)
+            .header(header::LOCATION, return_to)
+            .header(
+                header::SET_COOKIE,
+                password::access_cookie(&info, &state.access_key),
+            )
+            .body(boxed(String::new()))
+            .unwrap(),
+        Ok(false) => password_page(&info, true, &return_to, &state.config),
+        Err(retry_after) => password::too_many_attempts(retry_after),
+    }
+}
+
+async fn delete(
+    Path(file): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    State(state): State<AppState>,
+) -> Response {
+    let uid = file;
+    let maybe_info = dbman::read_file_info(uid, &state.db);
+    if maybe_info.is_none() {
+        return Response::builder()
+            .status(404)
+            .body(boxed("404".to_string())) // I have no idea why this needs to be boxed but whatever
+            .unwrap();
+    }
+    let info = maybe_info.unwrap();
+
+    let (body, nonce) = render_file(
+        "delete.hbs",
+        &json!({
+            "id": info.id,
+            "filename": info.name,
+            "key": params.get("key"),
         }),
     )
     .expect("rendering failed");
 
     Response::builder()
         .header(header::CONTENT_TYPE, "text/html")
+        .header(
+            header::CONTENT_SECURITY_POLICY,
+            security::page_csp(&nonce, &state.config),
+        )
         .body(boxed(body)) // I have no idea why this needs to be boxed but whatever
         .unwrap()
 }
@@ -92,4 +531,8 @@ pub fn get_pages_router() -> Router<AppState> {
     Router::new()
         .route("/", get(upload))
         .route("/file/:file", get(file))
+        .route("/file/:file/delete", get(delete))
+        .route("/file/:file/password", post(unlock))
+        .route("/p/:file", get(paste))
+        .route("/p/:file/raw", get(paste_raw))
 }
@@ -0,0 +1,306 @@
+/*
+# Password protected files
+
+Only an argon2 hash of the password is stored. Argon2 is slow and memory hungry on purpose,
+so passwords are only ever checked behind the password ratelimit, on the blocking thread pool.
+
+Once the password page accepted a password, the browser gets an access cookie which is cheap
+to check: when it runs out and an HMAC of the file id, the password hash and that date.
+Since the password hash is in there, changing the password makes old cookies useless.
+The key for the HMAC is made on the first start and kept in the database.
+*/
+
+use std::{error::Error, time::Duration};
+
+use argon2::{
+    password_hash::{rand_core::OsRng as SaltRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+use axum::{
+    body::boxed,
+    http::{header, HeaderMap, StatusCode},
+    response::Response,
+};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use sha2::Sha256;
+use sled::Db;
+
+use crate::{dbman::FileInfo, ratelimit, AppState};
+
+/// Where the access cookie key is kept in the database
+const ACCESS_KEY_NAME: &str = "access_cookie_key";
+
+/// Seconds until the password has to be typed in again
+const ACCESS_COOKIE_LIFETIME: i64 = 24 * 60 * 60;
+
+/// Hashes the password of a protected file with argon2, the salt is part of the result.
+pub async fn hash_password(password: String) -> String {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut SaltRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .expect("couldn't hash password")
+            .to_string()
+    })
+    .await
+    .expect("password hashing task panicked")
+}
+
+/// Checks a password against a hash from `hash_password`, in constant time
+async fn verify_password(password: String, password_hash: String) -> bool {
+    tokio::task::spawn_blocking(move || {
+        PasswordHash::new(&password_hash).is_ok_and(|parsed| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok()
+        })
+    })
+    .await
+    .unwrap_or(false)
+}
+
+/// Loads the key access cookies are signed with, or makes one if there isn't one yet
+pub fn load_access_key(db: &Db) -> Result<[u8; 32], Box<dyn Error>> {
+    let mut key = [0; 32];
+    OsRng.fill_bytes(&mut key);
+    // only stored if there's nothing there
+    let _ = db.compare_and_swap(ACCESS_KEY_NAME, None as Option<&[u8]>, Some(&key[..]))?;
+    let stored = db
+        .get(ACCESS_KEY_NAME)?
+        .ok_or("access cookie key is missing")?;
+    Ok(stored.as_ref().try_into()?)
+}
+
+fn cookie_name(id: &str) -> String {
+    format!("filebin_access_{}", id)
+}
+
+fn access_mac(id: &str, password_hash: &str, expires: i64, key: &[u8; 32]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac takes keys of any length");
+    mac.update(format!("{}\n{}\n{}", id, password_hash, expires).as_bytes());
+    mac
+}
+
+/// Set-Cookie value for someone who just typed in the right password
+pub fn access_cookie(info: &FileInfo, key: &[u8; 32]) -> String {
+    let password_hash = info.password_hash.as_deref().unwrap_or_default();
+    let expires = Utc::now().timestamp() + ACCESS_COOKIE_LIFETIME;
+    let signature = access_mac(&info.id, password_hash, expires, key)
+        .finalize()
+        .into_bytes();
+    format!(
+        "{}={}.{}; Max-Age={}; Path=/; HttpOnly; Secure; SameSite=Strict",
+        cookie_name(&info.id),
+        expires,
+        base64::encode_config(signature, base64::URL_SAFE_NO_PAD),
+        ACCESS_COOKIE_LIFETIME
+    )
+}
+
+fn has_access_cookie(
+    info: &FileInfo,
+    password_hash: &str,
+    headers: &HeaderMap,
+    key: &[u8; 32],
+) -> bool {
+    let cookie_prefix = format!("{}=", cookie_name(&info.id));
+    let Some(value) = headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(';'))
+        .find_map(|x| x.trim().strip_prefix(&cookie_prefix))
+    else {
+        return false;
+    };
+
+    let Some((expires, signature)) = value.split_once('.') else {
+        return false;
+    };
+    let (Ok(expires), Ok(signature)) = (
+        expires.parse::<i64>(),
+        base64::decode_config(signature, base64::URL_SAFE_NO_PAD),
+    ) else {
+        return false;
+    };
+    expires > Utc::now().timestamp()
+        && access_mac(&info.id, password_hash, expires, key)
+            .verify_slice(&signature)
+            .is_ok()
+}
+
+/// Checks a password someone sent, unless they already tried too many.
+/// Returns how long they have to wait in that case.
+pub async fn check_password(
+    info: &FileInfo,
+    password: &str,
+    ratelimit_token: &str,
+    state: &AppState,
+) -> Result<bool, Duration> {
+    let Some(password_hash) = info.password_hash.clone() else {
+        return Ok(true);
+    };
+    let retry_after =
+        ratelimit::password_ratelimit(ratelimit_token, state).expect("couldn't check ratelimiter");
+    if let Some(retry_after) = retry_after {
+        return Err(retry_after);
+    }
+    Ok(verify_password(password.to_string(), password_hash).await)
+}
+
+pub enum FileAccess {
+    Allowed,
+    Denied,
+    /// Too many wrong passwords, try again after this long
+    Ratelimited(Duration),
+}
+
+/// Whether a request may see a file. Protected files need the access cookie from the
+/// password page, or the password in a `X-File-Password` header for api clients.
+pub async fn file_access(
+    info: &FileInfo,
+    headers: &HeaderMap,
+    ratelimit_token: &str,
+    state: &AppState,
+) -> FileAccess {
+    let Some(password_hash) = &info.password_hash else {
+        return FileAccess::Allowed;
+    };
+    if has_access_cookie(info, password_hash, headers, &state.access_key) {
+        return FileAccess::Allowed;
+    }
+
+    let Some(password) = headers.get("x-file-password").and_then(|x| x.to_str().ok()) else {
+        return FileAccess::Denied;
+    };
+    match check_password(info, password, ratelimit_token, state).await {
+        Ok(true) => FileAccess::Allowed,
+        Ok(false) => FileAccess::Denied,
+        Err(retry_after) => FileAccess::Ratelimited(retry_after),
+    }
+}
+
+pub fn too_many_attempts(retry_after: Duration) -> Response {
+    Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header(header::RETRY_AFTER, retry_after.as_secs().max(1))
+        .body(boxed("Too many password attempts, slow down".to_string()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn protected_file(password_hash: &str) -> FileInfo {
+        FileInfo {
+            mime_type: "text/plain".to_string(),
+            detected_mime_type: "text/plain".to_string(),
+            upload_date: Utc::now(),
+            deletion_key: String::new(),
+            id: "abc".to_string(),
+            name: "a.txt".to_string(),
+            size: 3,
+            hash: String::new(),
+            expiry_date: None,
+            description: None,
+            preview: true,
+            password_hash: Some(password_hash.to_string()),
+            revision: 0,
+            scan_result: crate::scanner::ScanResult::NotScanned,
+            hidden: false,
+        }
+    }
+
+    /// Request headers with the cookie from a Set-Cookie value
+    fn cookie_headers(set_cookie: &str) -> HeaderMap {
+        let cookie = set_cookie.split(';').next().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            format!("other=1; {}", cookie).parse().unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn access_cookies_are_checked() {
+        let key = [1; 32];
+        let info = protected_file("hash");
+        let headers = cookie_headers(&access_cookie(&info, &key));
+        assert!(has_access_cookie(&info, "hash", &headers, &key));
+
+        // another key, another password or another file
+        assert!(!has_access_cookie(&info, "hash", &headers, &[2; 32]));
+        assert!(!has_access_cookie(&info, "new hash", &headers, &key));
+        let other_file = FileInfo {
+            id: "abd".to_string(),
+            ..info.clone()
+        };
+        let other_headers = cookie_headers(&access_cookie(&info, &key).replacen(
+            "filebin_access_abc",
+            "filebin_access_abd",
+            1,
+        ));
+        assert!(!has_access_cookie(
+            &other_file,
+            "hash",
+            &other_headers,
+            &key
+        ));
+    }
+
+    #[test]
+    fn expired_and_tampered_cookies_are_refused() {
+        let key = [1; 32];
+        let info = protected_file("hash");
+        let expired = Utc::now().timestamp() - 1;
+        let signature = access_mac("abc", "hash", expired, &key)
+            .finalize()
+            .into_bytes();
+        let cookie = format!(
+            "filebin_access_abc={}.{}",
+            expired,
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        );
+        assert!(!has_access_cookie(
+            &info,
+            "hash",
+            &cookie_headers(&cookie),
+            &key
+        ));
+
+        let valid = access_cookie(&info, &key);
+        let (value, _) = valid.split_once(';').unwrap();
+        let (expires, signature) = value.split_once('.').unwrap();
+        let later = format!("{}9.{}", expires, signature);
+        assert!(!has_access_cookie(
+            &info,
+            "hash",
+            &cookie_headers(&later),
+            &key
+        ));
+        assert!(!has_access_cookie(
+            &info,
+            "hash",
+            &cookie_headers("filebin_access_abc=garbage"),
+            &key
+        ));
+    }
+
+    #[test]
+    fn access_cookies_are_not_readable_by_scripts() {
+        let cookie = access_cookie(&protected_file("hash"), &[1; 32]);
+        assert!(cookie.contains("; HttpOnly"));
+        assert!(cookie.contains("; Secure"));
+    }
+
+    #[test]
+    fn access_key_is_kept() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let key = load_access_key(&db).unwrap();
+        assert_eq!(load_access_key(&db).unwrap(), key);
+    }
+}
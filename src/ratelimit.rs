@@ -0,0 +1,389 @@
+/*
+Every ratelimit token has one bucket per kind of limit, stored with keys like these:
+`ratelimit:[TOKEN]` for uploaded bytes,
+`download_requests:[TOKEN]` and `download_bytes:[TOKEN]` for downloads,
+`reports:[TOKEN]` for abuse reports, `passwords:[TOKEN]` for password attempts.
+A bucket holds the credits used so far, which drain over time: a full bucket
+is empty again after the limit's period. Empty buckets are swept up
+every now and then, so clients who never come back don't leave anything behind.
+
+The global egress cap is a bucket too, but only in memory.
+*/
+
+use std::{
+    error::Error,
+    future::Future,
+    io,
+    net::IpAddr,
+    pin::Pin,
+    sync::Mutex,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use bincode::{config::Configuration, decode_from_slice, encode_to_vec, Decode, Encode};
+use chrono::Utc;
+use ipnet::IpNet;
+use sled::{Db, IVec};
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::{AppConfig, AppState};
+
+const BINCODE_CONFIG: Configuration = bincode::config::standard();
+
+/// Download bytes are taken from the buckets in slices this big, not for every read
+const DOWNLOAD_SLICE: u64 = 256 * 1024;
+
+/// How many credits can be used per period
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    amount: u64,
+    period_secs: u64,
+}
+
+impl Limit {
+    pub fn uploaded_bytes(config: &AppConfig) -> Limit {
+        Limit {
+            amount: config.ratelimit_period_byte_limit.get_bytes() as u64,
+            period_secs: config.ratelimit_period_length,
+        }
+    }
+
+    pub fn download_requests(config: &AppConfig) -> Limit {
+        Limit {
+            amount: config.download_ratelimit_requests,
+            period_secs: config.download_ratelimit_period_length,
+        }
+    }
+
+    pub fn downloaded_bytes(config: &AppConfig) -> Limit {
+        Limit {
+            amount: config.download_ratelimit_byte_limit.get_bytes() as u64,
+            period_secs: config.download_ratelimit_period_length,
+        }
+    }
+
+    pub fn password_attempts(config: &AppConfig) -> Limit {
+        Limit {
+            amount: config.password_ratelimit_requests,
+            period_secs: config.password_ratelimit_period_length,
+        }
+    }
+
+    pub fn reports(config: &AppConfig) -> Limit {
+        Limit {
+            amount: config.report_ratelimit_requests,
+            period_secs: config.report_ratelimit_period_length,
+        }
+    }
+
+    fn egress(config: &AppConfig) -> Option<Limit> {
+        config.egress_limit.map(|x| Limit {
+            amount: x.get_bytes() as u64,
+            period_secs: 1,
+        })
+    }
+
+    /// How long it takes for `credits` to drain
+    fn drain_time(self, credits: u64) -> Duration {
+        Duration::from_secs_f64(
+            credits as f64 / self.amount.max(1) as f64 * self.period_secs as f64,
+        )
+    }
+}
+
+#[derive(Encode, Decode, Default, Debug, Clone, Copy)]
+struct Bucket {
+    /// Credits used, as of `updated`
+    used: u64,
+    /// Unix timestamp in milliseconds
+    updated: i64,
+}
+
+impl Bucket {
+    fn decode(bytes: &[u8]) -> Option<Bucket> {
+        decode_from_slice(bytes, BINCODE_CONFIG).ok().map(|x| x.0)
+    }
+
+    /// The bucket as it is at `now`, after draining since the last update
+    fn drained(self, now: i64, limit: Limit) -> Bucket {
+        let period_millis = limit.period_secs as f64 * 1000.0;
+        let elapsed = (now - self.updated).max(0) as f64;
+        let drained = (elapsed / period_millis * limit.amount as f64) as u64;
+        Bucket {
+            used: self.used.saturating_sub(drained),
+            updated: now,
+        }
+    }
+
+    /// Uses up to `wanted` credits, returns how many were left to use
+    fn take(&mut self, wanted: u64, limit: Limit) -> u64 {
+        let taken = wanted.min(limit.amount.saturating_sub(self.used));
+        self.used += taken;
+        taken
+    }
+}
+
+/// Ratelimit token for a client, the network it's in as configured by the ratelimit prefixes
+pub fn ip_ratelimit_token(ip: IpAddr, config: &AppConfig) -> String {
+    let ip = ip.to_canonical();
+    let prefix = match ip {
+        IpAddr::V4(_) => config.ratelimit_ipv4_prefix,
+        IpAddr::V6(_) => config.ratelimit_ipv6_prefix,
+    };
+    match IpNet::new(ip, prefix) {
+        Ok(network) => network.trunc().to_string(),
+        Err(_) => ip.to_string(),
+    }
+}
+
+/// Takes up to `wanted` credits from a bucket in the database, returns how many it got.
+/// With `all_or_nothing` it only takes them if they're all there.
+fn take_credits(
+    key: &str,
+    wanted: u64,
+    limit: Limit,
+    all_or_nothing: bool,
+    db: &Db,
+) -> Result<u64, Box<dyn Error>> {
+    let now = Utc::now().timestamp_millis();
+    // the closure can run more than once if someone else changes the bucket meanwhile,
+    // only the last run counts
+    let mut taken = 0;
+    db.update_and_fetch(key, |old| {
+        let mut bucket = old
+            .and_then(Bucket::decode)
+            .unwrap_or_default()
+            .drained(now, limit);
+        let available = limit.amount.saturating_sub(bucket.used);
+        taken = if all_or_nothing && available < wanted {
+            0
+        } else {
+            bucket.take(wanted, limit)
+        };
+        Some(encode_to_vec(bucket, BINCODE_CONFIG).expect("couldn't encode bucket"))
+    })?;
+    Ok(taken)
+}
+
+/// Checks if `token` can upload `credit_cost` more bytes, and spends them if so.
+/// With `dry` set nothing is spent.
+pub fn timebased_ratelimit(
+    token: &str,
+    credit_cost: u64,
+    state: &AppState,
+    dry: bool,
+) -> Result<bool, Box<dyn Error>> {
+    let limit = Limit::uploaded_bytes(&state.config);
+    let key = format!("ratelimit:{}", token);
+
+    if dry {
+        let bucket = state
+            .db
+            .get(&key)?
+            .and_then(|x| Bucket::decode(&x))
+            .unwrap_or_default()
+            .drained(Utc::now().timestamp_millis(), limit);
+        return Ok(bucket.used + credit_cost <= limit.amount);
+    }
+
+    Ok(take_credits(&key, credit_cost, limit, true, &state.db)? == credit_cost)
+}
+
+/// Counts a download request, returns how long to wait if there were too many.
+pub fn download_request_ratelimit(
+    token: &str,
+    state: &AppState,
+) -> Result<Option<Duration>, Box<dyn Error>> {
+    let limit = Limit::download_requests(&state.config);
+    let key = format!("download_requests:{}", token);
+    if take_credits(&key, 1, limit, true, &state.db)? == 1 {
+        Ok(None)
+    } else {
+        Ok(Some(limit.drain_time(1)))
+    }
+}
+
+/// Counts an abuse report, returns how long to wait if there were too many.
+pub fn report_ratelimit(token: &str, state: &AppState) -> Result<Option<Duration>, Box<dyn Error>> {
+    let limit = Limit::reports(&state.config);
+    let key = format!("reports:{}", token);
+    if take_credits(&key, 1, limit, true, &state.db)? == 1 {
+        Ok(None)
+    } else {
+        Ok(Some(limit.drain_time(1)))
+    }
+}
+
+/// Counts a password attempt, returns how long to wait if there were too many.
+/// Checking a password is expensive, so this also keeps anyone from hogging the server with it.
+pub fn password_ratelimit(
+    token: &str,
+    state: &AppState,
+) -> Result<Option<Duration>, Box<dyn Error>> {
+    let limit = Limit::password_attempts(&state.config);
+    let key = format!("passwords:{}", token);
+    if take_credits(&key, 1, limit, true, &state.db)? == 1 {
+        Ok(None)
+    } else {
+        Ok(Some(limit.drain_time(1)))
+    }
+}
+
+static EGRESS_BUCKET: Mutex<Bucket> = Mutex::new(Bucket {
+    used: 0,
+    updated: 0,
+});
+
+/// Waits until it can take some download bytes from both the client's bucket and
+/// the global egress bucket, returns how many it got.
+async fn take_download_bytes(token: String, wanted: u64, state: AppState) -> u64 {
+    let byte_limit = Limit::downloaded_bytes(&state.config);
+    let egress_limit = Limit::egress(&state.config);
+    let key = format!("download_bytes:{}", token);
+    loop {
+        let mut wanted = wanted;
+
+        // only peeked at first, so it isn't wasted if the client's bucket is empty
+        if let Some(egress_limit) = egress_limit {
+            let mut bucket = EGRESS_BUCKET.lock().unwrap();
+            *bucket = bucket.drained(Utc::now().timestamp_millis(), egress_limit);
+            wanted = wanted.min(egress_limit.amount.saturating_sub(bucket.used));
+        }
+
+        let wait = if wanted == 0 {
+            egress_limit.map_or(Duration::ZERO, |x| {
+                x.drain_time(DOWNLOAD_SLICE.min(x.amount))
+            })
+        } else {
+            match take_credits(&key, wanted, byte_limit, false, &state.db) {
+                Ok(0) => byte_limit.drain_time(DOWNLOAD_SLICE),
+                Ok(taken) => {
+                    if egress_limit.is_some() {
+                        EGRESS_BUCKET.lock().unwrap().used += taken;
+                    }
+                    return taken;
+                }
+                Err(e) => {
+                    // better to let the download through than to break it
+                    log::error!("Couldn't check download ratelimit: {}", e);
+                    return wanted;
+                }
+            }
+        };
+
+        // a bit at least, so clients on a tiny budget don't spin
+        tokio::time::sleep(wait.max(Duration::from_millis(10))).await;
+    }
+}
+
+type TakeBytesFuture = Pin<Box<dyn Future<Output = u64> + Send>>;
+
+/// Reads from `R` only as fast as the download ratelimits allow
+pub struct ThrottledReader<R> {
+    inner: R,
+    token: String,
+    state: AppState,
+    /// Bytes that can be read before more have to be taken from the buckets
+    allowance: u64,
+    /// Bytes left in the body, so small files aren't charged a whole slice
+    remaining: u64,
+    pending: Option<TakeBytesFuture>,
+}
+
+impl<R> ThrottledReader<R> {
+    /// `length` is how many bytes `inner` is expected to give
+    pub fn new(inner: R, length: u64, token: String, state: AppState) -> Self {
+        Self {
+            inner,
+            token,
+            state,
+            allowance: 0,
+            remaining: length,
+            pending: None,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ThrottledReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.allowance == 0 {
+            match &mut this.pending {
+                Some(pending) => {
+                    this.allowance = ready!(pending.as_mut().poll(cx));
+                    this.pending = None;
+                }
+                None => {
+                    // at least one byte, in case the length was off and there's more
+                    let wanted = DOWNLOAD_SLICE.min(this.remaining).max(1);
+                    this.pending = Some(Box::pin(take_download_bytes(
+                        this.token.clone(),
+                        wanted,
+                        this.state.clone(),
+                    )));
+                }
+            }
+        }
+
+        let max_length = buf.remaining().min(this.allowance as usize);
+        let mut limited_buf = ReadBuf::new(buf.initialize_unfilled_to(max_length));
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited_buf))?;
+        let read = limited_buf.filled().len();
+        buf.advance(read);
+        this.allowance -= read as u64;
+        this.remaining = this.remaining.saturating_sub(read as u64);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Removes buckets that have fully drained, and any left over from the old key layout.
+pub fn delete_empty_buckets(state: &AppState) -> Result<usize, Box<dyn Error>> {
+    let now = Utc::now().timestamp_millis();
+    let mut deleted = 0;
+    let kinds = [
+        ("ratelimit:", Limit::uploaded_bytes(&state.config)),
+        (
+            "download_requests:",
+            Limit::download_requests(&state.config),
+        ),
+        ("download_bytes:", Limit::downloaded_bytes(&state.config)),
+        ("reports:", Limit::reports(&state.config)),
+        ("passwords:", Limit::password_attempts(&state.config)),
+    ];
+    for (prefix, limit) in kinds {
+        for maybe_pair in state.db.scan_prefix(prefix) {
+            let (key, value) = maybe_pair?;
+            let is_empty = Bucket::decode(&value).is_none_or(|x| x.drained(now, limit).used == 0);
+            if !is_empty {
+                continue;
+            }
+            // only if nobody used it since we looked
+            if state
+                .db
+                .compare_and_swap(&key, Some(&value), None as Option<IVec>)?
+                .is_ok()
+            {
+                deleted += 1;
+            }
+        }
+    }
+    Ok(deleted)
+}
+
+/// Sweeps up empty buckets every now and then, never returns.
+pub async fn bucket_sweeper(state: AppState) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 10));
+    loop {
+        interval.tick().await;
+        match delete_empty_buckets(&state) {
+            Ok(0) => {}
+            Ok(x) => log::debug!("Deleted {} empty ratelimit buckets", x),
+            Err(e) => log::error!("Couldn't delete empty ratelimit buckets: {}", e),
+        }
+    }
+}
@@ -0,0 +1,89 @@
+/*
+Abuse reports are kept in their own sled tree, with keys like `[FILE ID]:[REPORT ID]`
+so all reports about a file can be found with a prefix scan.
+*/
+
+use std::{collections::HashSet, error::Error};
+
+use bincode::{config::Configuration, decode_from_slice, encode_to_vec, Decode, Encode};
+use chrono::{DateTime, Utc};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::Serialize;
+use sled::{Db, Tree};
+
+const BINCODE_CONFIG: Configuration = bincode::config::standard();
+
+#[derive(Encode, Decode, Serialize, Debug, Clone)]
+pub struct Report {
+    pub id: String,
+    pub file_id: String,
+    #[bincode(with_serde)]
+    pub date: DateTime<Utc>,
+    pub reason: String,
+    /// How to reach the reporter, if they want to be reached
+    pub contact: Option<String>,
+    /// Ratelimit token of the reporter, so one person can't hide a file on their own
+    pub reporter: String,
+}
+
+fn tree(db: &Db) -> Result<Tree, Box<dyn Error>> {
+    Ok(db.open_tree("reports")?)
+}
+
+/// Stores a new report, returns how many different people reported the file so far
+pub fn add(
+    file_id: &str,
+    reason: String,
+    contact: Option<String>,
+    reporter: String,
+    db: &Db,
+) -> Result<usize, Box<dyn Error>> {
+    let report = Report {
+        id: Alphanumeric.sample_string(&mut rand::thread_rng(), 12),
+        file_id: file_id.to_string(),
+        date: Utc::now(),
+        reason,
+        contact,
+        reporter,
+    };
+    tree(db)?.insert(
+        format!("{}:{}", file_id, report.id),
+        encode_to_vec(&report, BINCODE_CONFIG)?,
+    )?;
+
+    let reporters: HashSet<String> = for_file(file_id, db)?
+        .into_iter()
+        .map(|x| x.reporter)
+        .collect();
+    Ok(reporters.len())
+}
+
+pub fn for_file(file_id: &str, db: &Db) -> Result<Vec<Report>, Box<dyn Error>> {
+    let mut reports = vec![];
+    for maybe_pair in tree(db)?.scan_prefix(format!("{}:", file_id)) {
+        let (_, value) = maybe_pair?;
+        reports.push(decode_from_slice(&value, BINCODE_CONFIG)?.0);
+    }
+    Ok(reports)
+}
+
+/// Every open report, oldest file id first
+pub fn all(db: &Db) -> Result<Vec<Report>, Box<dyn Error>> {
+    let mut reports = vec![];
+    for maybe_pair in tree(db)?.iter() {
+        let (_, value) = maybe_pair?;
+        reports.push(decode_from_slice(&value, BINCODE_CONFIG)?.0);
+    }
+    Ok(reports)
+}
+
+/// Removes all reports about a file, returns how many there were
+pub fn remove_for_file(file_id: &str, db: &Db) -> Result<usize, Box<dyn Error>> {
+    let tree = tree(db)?;
+    let mut removed = 0;
+    for maybe_key in tree.scan_prefix(format!("{}:", file_id)).keys() {
+        tree.remove(maybe_key?)?;
+        removed += 1;
+    }
+    Ok(removed)
+}
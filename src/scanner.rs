@@ -0,0 +1,190 @@
+use std::{error::Error, path::PathBuf, time::Duration};
+
+use axum::async_trait;
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UnixStream},
+};
+
+/// Outcome of scanning an upload, stored in its `FileInfo`
+#[derive(Encode, Decode, Deserialize, Serialize, PartialEq, Eq, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanResult {
+    /// No scanner is configured
+    NotScanned,
+    Clean,
+    /// Name of what the scanner found
+    Infected(String),
+    /// The scanner couldn't be reached or gave an error
+    Failed(String),
+}
+
+impl ScanResult {
+    pub fn is_clean(&self) -> bool {
+        matches!(self, ScanResult::NotScanned | ScanResult::Clean)
+    }
+}
+
+pub type ScanError = Box<dyn Error + Send + Sync>;
+
+/// Something that can check files for malware before they're stored
+#[async_trait]
+pub trait Scanner: Send + Sync {
+    /// Only returns `Clean` or `Infected`, everything else is an error
+    async fn scan(&self, bytes: &[u8]) -> Result<ScanResult, ScanError>;
+}
+
+/// Where clamd listens
+#[derive(Debug, Clone)]
+enum ClamdAddress {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+/// ClamAV daemon, spoken to with the INSTREAM command
+pub struct Clamd {
+    address: ClamdAddress,
+}
+
+/// clamd refuses chunks larger than its StreamMaxLength, this is well below the default
+const CLAMD_CHUNK_SIZE: usize = 64 * 1024;
+
+const CLAMD_TIMEOUT: Duration = Duration::from_secs(120);
+
+impl Clamd {
+    /// `address` is a path to a unix socket like `/run/clamav/clamd.ctl`,
+    /// or a tcp address like `127.0.0.1:3310`
+    pub fn new(address: &str) -> Clamd {
+        let address = match address.strip_prefix("unix:") {
+            Some(path) => ClamdAddress::Unix(path.into()),
+            None if address.starts_with('/') => ClamdAddress::Unix(address.into()),
+            None => ClamdAddress::Tcp(address.trim_start_matches("tcp:").to_string()),
+        };
+        Clamd { address }
+    }
+
+    async fn instream<S: AsyncRead + AsyncWrite + Unpin>(
+        mut stream: S,
+        bytes: &[u8],
+    ) -> Result<String, ScanError> {
+        // the z prefix means replies end with a null byte
+        stream.write_all(b"zINSTREAM\0").await?;
+        for chunk in bytes.chunks(CLAMD_CHUNK_SIZE) {
+            stream
+                .write_all(&(chunk.len() as u32).to_be_bytes())
+                .await?;
+            stream.write_all(chunk).await?;
+        }
+        stream.write_all(&0u32.to_be_bytes()).await?;
+        stream.flush().await?;
+
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await?;
+        Ok(String::from_utf8_lossy(&reply)
+            .trim_end_matches('\0')
+            .trim()
+            .to_string())
+    }
+
+    /// Replies look like `stream: OK` or `stream: Eicar-Signature FOUND`, anything else is an error
+    fn parse_reply(reply: &str) -> Result<ScanResult, ScanError> {
+        let result = reply.strip_prefix("stream: ").unwrap_or(reply);
+        if result == "OK" {
+            return Ok(ScanResult::Clean);
+        }
+        if let Some(name) = result.strip_suffix(" FOUND") {
+            return Ok(ScanResult::Infected(name.to_string()));
+        }
+        Err(format!("clamd replied: {}", reply).into())
+    }
+}
+
+#[async_trait]
+impl Scanner for Clamd {
+    async fn scan(&self, bytes: &[u8]) -> Result<ScanResult, ScanError> {
+        let reply = tokio::time::timeout(CLAMD_TIMEOUT, async {
+            match &self.address {
+                ClamdAddress::Tcp(x) => Clamd::instream(TcpStream::connect(x).await?, bytes).await,
+                ClamdAddress::Unix(x) => {
+                    Clamd::instream(UnixStream::connect(x).await?, bytes).await
+                }
+            }
+        })
+        .await
+        .map_err(|_| "clamd timed out")??;
+
+        Clamd::parse_reply(&reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, DuplexStream};
+
+    use super::*;
+
+    /// Reads an INSTREAM request like clamd does and answers with `reply`.
+    /// Returns the length of every chunk it got, and the bytes put back together.
+    async fn fake_clamd(mut stream: DuplexStream, reply: &str) -> (Vec<usize>, Vec<u8>) {
+        let mut command = [0; 10];
+        stream.read_exact(&mut command).await.unwrap();
+        assert_eq!(&command, b"zINSTREAM\0");
+
+        let mut lengths = vec![];
+        let mut bytes = vec![];
+        loop {
+            let length = stream.read_u32().await.unwrap() as usize;
+            if length == 0 {
+                break;
+            }
+            let mut chunk = vec![0; length];
+            stream.read_exact(&mut chunk).await.unwrap();
+            lengths.push(length);
+            bytes.extend_from_slice(&chunk);
+        }
+
+        stream.write_all(reply.as_bytes()).await.unwrap();
+        stream.write_all(b"\0").await.unwrap();
+        (lengths, bytes)
+    }
+
+    async fn scan_with_reply(bytes: &[u8], reply: &str) -> (Vec<usize>, String) {
+        // a small buffer, so writing has to wait for the other side to read
+        let (client, server) = duplex(1024);
+        let reply = reply.to_string();
+        let clamd = tokio::spawn(async move { fake_clamd(server, &reply).await });
+        let answer = Clamd::instream(client, bytes).await.unwrap();
+        let (lengths, received) = clamd.await.unwrap();
+        assert_eq!(received, bytes);
+        (lengths, answer)
+    }
+
+    #[tokio::test]
+    async fn streams_in_chunks() {
+        let bytes: Vec<u8> = (0..2 * CLAMD_CHUNK_SIZE + 100).map(|x| x as u8).collect();
+        let (lengths, answer) = scan_with_reply(&bytes, "stream: OK").await;
+        assert_eq!(lengths, [CLAMD_CHUNK_SIZE, CLAMD_CHUNK_SIZE, 100]);
+        assert_eq!(answer, "stream: OK");
+    }
+
+    #[tokio::test]
+    async fn empty_files_are_just_the_terminator() {
+        let (lengths, _) = scan_with_reply(&[], "stream: OK").await;
+        assert!(lengths.is_empty());
+    }
+
+    #[tokio::test]
+    async fn parses_replies() {
+        let (_, answer) = scan_with_reply(b"X5O!P%", "stream: Eicar-Signature FOUND").await;
+        assert_eq!(
+            Clamd::parse_reply(&answer).unwrap(),
+            ScanResult::Infected("Eicar-Signature".to_string())
+        );
+
+        assert_eq!(Clamd::parse_reply("stream: OK").unwrap(), ScanResult::Clean);
+        assert!(Clamd::parse_reply("INSTREAM size limit exceeded. ERROR").is_err());
+        assert!(Clamd::parse_reply("").is_err());
+    }
+}
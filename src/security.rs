@@ -0,0 +1,71 @@
+use axum::{
+    http::{header, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use rand::{rngs::OsRng, Rng};
+
+use crate::AppConfig;
+
+/// Random value that lets a page's own inline scripts run, a new one for every response
+pub fn nonce() -> String {
+    base64::encode(OsRng.gen::<[u8; 16]>())
+}
+
+/// Origin of the user content domain, if there is one
+fn user_content_origin(config: &AppConfig) -> &str {
+    config
+        .user_content_url
+        .as_deref()
+        .map(|x| x.trim_end_matches('/'))
+        .unwrap_or_default()
+}
+
+/// Policy for html pages. Only scripts from filebin itself and inline scripts with the
+/// nonce can run, previews can only be embedded from filebin or the user content domain.
+pub fn page_csp(nonce: &str, config: &AppConfig) -> String {
+    let user_content = user_content_origin(config);
+    format!(
+        "default-src 'self'; \
+        script-src 'self' 'nonce-{nonce}'; \
+        style-src 'self' 'unsafe-inline'; \
+        img-src 'self' data: https: {user_content}; \
+        media-src 'self' {user_content}; \
+        frame-src 'self' {user_content}; \
+        object-src 'none'; \
+        base-uri 'none'; \
+        form-action 'self'; \
+        frame-ancestors 'none'"
+    )
+}
+
+/// Policy for uploaded files. They're sandboxed into a unique origin and can't run
+/// scripts, so inline html or svg can't touch filebin, and only filebin pages can frame them.
+pub fn user_content_csp(config: &AppConfig) -> String {
+    let public_url = config
+        .public_url
+        .as_deref()
+        .map(|x| x.trim_end_matches('/'))
+        .unwrap_or_default();
+    format!(
+        "sandbox; \
+        default-src 'none'; \
+        img-src 'self' data:; \
+        media-src 'self'; \
+        style-src 'unsafe-inline'; \
+        frame-ancestors 'self' {public_url}"
+    )
+}
+
+/// Adds headers every response should have, unless the handler set them already
+pub async fn security_headers<B>(request: Request<B>, next: Next<B>) -> Response {
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers
+        .entry(header::X_CONTENT_TYPE_OPTIONS)
+        .or_insert(HeaderValue::from_static("nosniff"));
+    headers
+        .entry(header::REFERRER_POLICY)
+        .or_insert(HeaderValue::from_static("same-origin"));
+    response
+}
@@ -0,0 +1,26 @@
+/// Guessing from the first few KiB is enough, and multibyte characters cut off at the end are ignored
+pub const TEXT_SNIFF_LENGTH: usize = 8 * 1024;
+
+/// Fallback for files we can't recognise
+pub const BINARY_MIME_TYPE: &str = "application/octet-stream";
+
+/// Detects the mime type of a file from its contents, ignoring what the uploader claimed.
+/// Files without a known signature are `text/plain` if they're valid UTF-8.
+pub fn detect(bytes: &[u8]) -> String {
+    if let Some(kind) = infer::get(bytes) {
+        return kind.mime_type().to_string();
+    }
+    if is_text(bytes) {
+        return "text/plain".to_string();
+    }
+    BINARY_MIME_TYPE.to_string()
+}
+
+fn is_text(bytes: &[u8]) -> bool {
+    let start = &bytes[..bytes.len().min(TEXT_SNIFF_LENGTH)];
+    match std::str::from_utf8(start) {
+        Ok(text) => !text.contains('\0'),
+        // an incomplete character at the end, because of the cut off
+        Err(e) => e.error_len().is_none() && !start[..e.valid_up_to()].contains(&0),
+    }
+}
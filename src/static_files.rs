@@ -9,6 +9,16 @@ use rust_embed::RustEmbed;
 #[folder = "static/"]
 struct Assets;
 
+/// Top level names of the static assets, like "lib" or "common.css"
+pub fn asset_names() -> impl Iterator<Item = String> {
+    let mut names: Vec<String> = Assets::iter()
+        .filter_map(|x| x.split('/').next().map(str::to_string))
+        .collect();
+    names.sort();
+    names.dedup();
+    names.into_iter()
+}
+
 // got most of this from https://github.com/frehberg/rust-vue-demo/blob/main/src/main.rs
 
 pub async fn static_handler(uri: Uri) -> Response {
@@ -24,9 +34,7 @@ pub async fn static_handler(uri: Uri) -> Response {
                 .body(body)
                 .unwrap()
         }
-        None => {
-            return not_found().await;
-        }
+        None => not_found().await,
     }
 }
 
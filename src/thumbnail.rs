@@ -0,0 +1,65 @@
+use std::{error::Error, io::Cursor};
+
+use image::{ImageFormat, ImageReader, Limits};
+
+use crate::{dbman::FileInfo, AppConfig};
+
+/// Mime types we can decode without any system libraries
+const THUMBNAIL_MIME_TYPES: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "image/bmp",
+];
+
+/// Thumbnails are always encoded as png
+pub const THUMBNAIL_MIME_TYPE: &str = "image/png";
+
+pub fn has_thumbnail(info: &FileInfo, config: &AppConfig) -> bool {
+    THUMBNAIL_MIME_TYPES.contains(&info.detected_mime_type.as_str())
+        && info.size as u128 <= config.thumbnail_source_size_limit.get_bytes()
+}
+
+/// Decodes an image and scales it down to fit in a `size` by `size` square.
+/// This is slow, so call it with spawn_blocking.
+pub fn generate(bytes: &[u8], size: u32) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let mut reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+
+    // small files can still decode to huge images
+    let mut limits = Limits::default();
+    limits.max_alloc = Some(512 * 1024 * 1024);
+    reader.limits(limits);
+
+    let image = reader.decode()?;
+    let (width, height) = fit_dimensions(image.width(), image.height(), size);
+    let thumbnail = image.thumbnail_exact(width, height);
+
+    let mut encoded = Cursor::new(vec![]);
+    thumbnail.write_to(&mut encoded, ImageFormat::Png)?;
+    Ok(encoded.into_inner())
+}
+
+/// How much of a file `dimensions` needs, the header can come after big metadata segments
+pub const DIMENSIONS_READ_LIMIT: u64 = 256 * 1024;
+
+/// Reads the width and height of an image from its header, the start of the file is enough.
+pub fn dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+/// Dimensions of the thumbnail of a `width` by `height` image, small images aren't scaled up.
+pub fn fit_dimensions(width: u32, height: u32, size: u32) -> (u32, u32) {
+    if width <= size && height <= size {
+        return (width, height);
+    }
+    let ratio = size as f64 / width.max(height) as f64;
+    (
+        ((width as f64 * ratio).round() as u32).max(1),
+        ((height as f64 * ratio).round() as u32).max(1),
+    )
+}
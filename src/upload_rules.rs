@@ -0,0 +1,150 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use regex::Regex;
+
+use crate::AppConfig;
+
+/// Why an upload isn't accepted
+pub enum Rejection {
+    UnsupportedType(String),
+    TooLarge(String),
+}
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response {
+        match self {
+            Rejection::UnsupportedType(x) => (StatusCode::UNSUPPORTED_MEDIA_TYPE, x),
+            Rejection::TooLarge(x) => (StatusCode::PAYLOAD_TOO_LARGE, x),
+        }
+        .into_response()
+    }
+}
+
+fn extension(file_name: &str) -> Option<String> {
+    file_name
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_lowercase())
+        .filter(|x| !x.is_empty())
+}
+
+fn normalize_extension(extension: &str) -> String {
+    extension.trim_start_matches('.').to_lowercase()
+}
+
+/// Checks the extension of a file name against the allowed and denied extensions
+pub fn check_extension(file_name: &str, config: &AppConfig) -> Result<(), Rejection> {
+    let extension = extension(file_name);
+    let is_denied = extension.as_ref().is_some_and(|ext| {
+        config
+            .denied_extensions
+            .iter()
+            .any(|x| normalize_extension(x) == *ext)
+    });
+    let is_allowed = config.allowed_extensions.is_empty()
+        || extension.as_ref().is_some_and(|ext| {
+            config
+                .allowed_extensions
+                .iter()
+                .any(|x| normalize_extension(x) == *ext)
+        });
+    if is_denied || !is_allowed {
+        return Err(Rejection::UnsupportedType(match extension {
+            Some(x) => format!("Files with the extension .{} aren't allowed", x),
+            None => "Files without an extension aren't allowed".to_string(),
+        }));
+    }
+    Ok(())
+}
+
+/// The regexes of the upload rules, compiled once when the config is loaded
+pub struct UploadRules {
+    allowed_mime: Option<Regex>,
+    denied_mime: Option<Regex>,
+    /// Mime regexes and the size limit in bytes for matching types
+    type_size_limits: Vec<(Regex, u64)>,
+    file_size_limit: u64,
+}
+
+impl UploadRules {
+    /// Fails if one of the regexes in the config is invalid
+    pub fn from_config(config: &AppConfig) -> Result<UploadRules, regex::Error> {
+        Ok(UploadRules {
+            allowed_mime: config
+                .allowed_upload_mime_regex
+                .as_deref()
+                .map(Regex::new)
+                .transpose()?,
+            denied_mime: config
+                .denied_upload_mime_regex
+                .as_deref()
+                .map(Regex::new)
+                .transpose()?,
+            type_size_limits: config
+                .type_size_limits
+                .iter()
+                .map(|x| Ok((Regex::new(&x.mime_regex)?, x.limit.get_bytes() as u64)))
+                .collect::<Result<_, regex::Error>>()?,
+            file_size_limit: config.file_size_limit.get_bytes() as u64,
+        })
+    }
+}
+
+/// Checks a mime type against the allowed and denied mime regexes
+pub fn check_mime_type(mime_type: &str, rules: &UploadRules) -> Result<(), Rejection> {
+    let is_allowed = rules
+        .allowed_mime
+        .as_ref()
+        .is_none_or(|x| x.is_match(mime_type));
+    let is_denied = rules
+        .denied_mime
+        .as_ref()
+        .is_some_and(|x| x.is_match(mime_type));
+    if is_denied || !is_allowed {
+        return Err(Rejection::UnsupportedType(format!(
+            "Files of type {} aren't allowed",
+            mime_type
+        )));
+    }
+    Ok(())
+}
+
+/// Largest allowed size of a file with this mime type, in bytes
+pub fn size_limit(mime_type: &str, rules: &UploadRules) -> u64 {
+    rules
+        .type_size_limits
+        .iter()
+        .filter(|(regex, _)| regex.is_match(mime_type))
+        .map(|(_, limit)| *limit)
+        .fold(rules.file_size_limit, u64::min)
+}
+
+pub fn check_size(size: usize, mime_type: &str, rules: &UploadRules) -> Result<(), Rejection> {
+    let limit = size_limit(mime_type, rules);
+    if size as u64 > limit {
+        return Err(Rejection::TooLarge(format!(
+            "Files of type {} can't be larger than {}",
+            mime_type,
+            byte_unit::Byte::from_bytes(limit as u128).get_appropriate_unit(true)
+        )));
+    }
+    Ok(())
+}
+
+/// Checks a whole upload, both the type the uploader claimed and the one we detected
+pub fn check(
+    file_name: &str,
+    claimed_mime_type: &str,
+    detected_mime_type: &str,
+    size: usize,
+    config: &AppConfig,
+    rules: &UploadRules,
+) -> Result<(), Rejection> {
+    check_extension(file_name, config)?;
+    for mime_type in [claimed_mime_type, detected_mime_type] {
+        check_mime_type(mime_type, rules)?;
+        check_size(size, mime_type, rules)?;
+    }
+    Ok(())
+}
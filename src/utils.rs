@@ -1,28 +1,38 @@
-use std::{
-    error::Error,
-    time::{SystemTime, UNIX_EPOCH},
+use axum::http::{header, HeaderMap};
+use chrono::{DateTime, Duration, Utc};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    rngs::OsRng,
+    seq::IteratorRandom,
 };
-
-use chrono::{DateTime, NaiveDateTime, Utc};
 use regex::Regex;
-use sled::Batch;
+use sha2::Sha256;
+use sha3::{Digest, Sha3_512};
+use subtle::{Choice, ConstantTimeEq};
 
-use crate::{AppConfig, AppState};
+use crate::{dbman::FileInfo, static_files, AppConfig, IdStyle};
 
-// what about Db.generate_id... switch?
-pub fn unique_id() -> String {
-    let time = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_nanos();
+/// Words for human friendly ids, one per line
+const WORDS: &str = include_str!("words.txt");
 
-    let stripped_bytes: Vec<u8> = time
-        .to_be_bytes()
-        .into_iter()
-        .filter(|&byte| byte != 0u8)
-        .collect();
+/// New random id for a file, taken from the os so ids can't be guessed.
+/// It could still collide with another file, so reserve it with `dbman::reserve_id`.
+pub fn unique_id(config: &AppConfig) -> String {
+    let mut rng = OsRng;
+    match config.id_style {
+        IdStyle::Random => Alphanumeric.sample_string(&mut rng, config.id_length),
+        IdStyle::Words => (0..config.id_words)
+            .map(|_| WORDS.lines().choose(&mut rng).unwrap())
+            .collect::<Vec<_>>()
+            .join("-"),
+    }
+}
 
-    base64::encode_config(stripped_bytes, base64::URL_SAFE).replace('=', "")
+/// The date in that many seconds, None if it's too far away to represent
+pub fn expiry_date_in(seconds: u64) -> Option<DateTime<Utc>> {
+    let duration = Duration::try_seconds(i64::try_from(seconds).ok()?)?;
+    Utc::now().checked_add_signed(duration)
 }
 
 /// Given the uid and a reference to the db, get the link used to download a file.
@@ -30,70 +40,227 @@ pub fn get_download_link(uid: String) -> String {
     format!("/api/file/{}", uid)
 }
 
-pub fn should_preview(mime_type: &str, config: &AppConfig) -> bool {
-    let display_filter = Regex::new(&config.allowed_preview_mime_regex).unwrap();
-    display_filter.is_match(mime_type)
-}
-
-// credits are bytes.
-// keys look like: ratelimit:{token}:{unix_timestamp}
-// TODO: clean up ratelimit:* to remove expired keys every now and then
-pub fn timebased_ratelimit(
-    token: &str,
-    credit_cost: u64,
-    state: &AppState,
-    dry: bool, // if true, don't add credit_cost to db
-) -> Result<bool, Box<dyn Error>> {
-    let prefix = format!("ratelimit:{}", token);
-    let ratelimit_keys = state.db.scan_prefix(&prefix);
-    let mut used_credits: u64 = 0;
-    let mut batch = Batch::default();
-    for maybe_pair in ratelimit_keys {
-        let pair = maybe_pair?;
-        let pay_date: DateTime<Utc> = DateTime::from_utc(
-            NaiveDateTime::from_timestamp(
-                String::from_utf8(
-                    pair.0
-                        .strip_prefix((prefix.clone() + ":").as_bytes())
-                        .ok_or("couldn't strip prefix")?
-                        .to_owned(),
-                )?
-                .parse::<i64>()?,
-                0,
-            ),
-            Utc,
-        );
-
-        if (Utc::now() - pay_date).num_seconds() as u64 > state.config.ratelimit_period_length {
-            batch.remove(pair.0)
-        } else {
-            used_credits += u64::from_le_bytes(
-                pair.1
-                    .to_vec()
-                    .try_into()
-                    .ok() // ugly hack to make custom error
-                    .ok_or("couldn't convert bytes to u64")?,
-            );
-        }
+fn hash_secret(secret: &str, salt: &str) -> String {
+    let mut hasher = Sha3_512::new();
+
+    hasher.update(secret);
+    hasher.update(salt);
+
+    let hashed_secret_raw = hasher.finalize();
+
+    base64::encode_config(hashed_secret_raw, base64::URL_SAFE).replace('=', "")
+}
+
+/// Hashes a deletion key so only the hash has to be stored.
+pub fn hash_deletion_key(deletion_key: &str, file_name: &str) -> String {
+    hash_secret(deletion_key, file_name) // salt, idk if needed but why not
+}
+
+/// Slugs that would be confusing or clash with other routes
+const RESERVED_SLUGS: &[&str] = &["api", "file", "p", "favicon.ico", "delete", "raw", "thumb"];
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "))
+}
+
+/// Compares against every token in constant time, so timing doesn't give away how much of one matched
+fn is_one_of(token: &str, tokens: &[String]) -> bool {
+    tokens
+        .iter()
+        .fold(Choice::from(0), |found, x| {
+            found | x.as_bytes().ct_eq(token.as_bytes())
+        })
+        .into()
+}
+
+/// Whether the request has one of the configured upload tokens as a bearer token
+pub fn is_upload_token_valid(headers: &HeaderMap, config: &AppConfig) -> bool {
+    bearer_token(headers).is_some_and(|token| is_one_of(token, &config.upload_tokens))
+}
+
+/// Whether the request has one of the configured admin tokens as a bearer token
+pub fn is_admin(headers: &HeaderMap, config: &AppConfig) -> bool {
+    bearer_token(headers).is_some_and(|token| is_one_of(token, &config.admin_tokens))
+}
+
+/// Checks a custom slug against the configured regex and the reserved words
+pub fn validate_slug(slug: &str, slug_regex: &Regex) -> Result<(), String> {
+    if !slug_regex.is_match(slug) {
+        return Err(format!("Slugs have to match {}", slug_regex));
+    }
+    let lowercase_slug = slug.to_lowercase();
+    if RESERVED_SLUGS.contains(&lowercase_slug.as_str())
+        || static_files::asset_names().any(|x| x.to_lowercase() == lowercase_slug)
+    {
+        return Err("This slug is reserved".to_string());
+    }
+    Ok(())
+}
+
+/// Characters that don't need escaping in RFC 5987 values
+const ATTR_CHAR: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!')
+    .remove(b'#')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'+')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'^')
+    .remove(b'_')
+    .remove(b'`')
+    .remove(b'|')
+    .remove(b'~');
+
+/// Cleans up a file name from an uploader, so it's safe to show and download.
+/// Only the part after the last path separator is kept and control characters are removed.
+pub fn sanitize_file_name(name: &str) -> String {
+    let base_name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let cleaned: String = base_name.chars().filter(|x| !x.is_control()).collect();
+    let cleaned = cleaned.trim();
+    if cleaned.is_empty() || cleaned == "." || cleaned == ".." {
+        return "file".to_string();
     }
+    cleaned.to_string()
+}
+
+/// Content-Disposition header value with an ASCII fallback name for old clients
+/// and the real name encoded as described in RFC 6266 and RFC 5987.
+pub fn content_disposition(disposition: &str, file_name: &str) -> String {
+    let fallback: String = file_name
+        .chars()
+        .map(|x| {
+            if x.is_ascii_graphic() && x != '"' && x != '\\' || x == ' ' {
+                x
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        disposition,
+        fallback,
+        utf8_percent_encode(file_name, ATTR_CHAR)
+    )
+}
+
+/// Hex encoded sha256 of file contents
+pub fn content_hash(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// Languages that can be picked when pasting text, as (extension, name, mime type)
+pub const PASTE_LANGUAGES: &[(&str, &str, &str)] = &[
+    ("txt", "Plain text", "text/plain"),
+    ("log", "Log", "text/plain"),
+    ("md", "Markdown", "text/markdown"),
+    ("json", "JSON", "application/json"),
+    ("toml", "TOML", "application/toml"),
+    ("yaml", "YAML", "application/yaml"),
+    ("sh", "Shell", "text/x-shellscript"),
+    ("rs", "Rust", "text/x-rust"),
+    ("py", "Python", "text/x-python"),
+    ("js", "JavaScript", "text/javascript"),
+    ("ts", "TypeScript", "text/x-typescript"),
+    ("go", "Go", "text/x-go"),
+    ("c", "C", "text/x-c"),
+    ("cpp", "C++", "text/x-c++"),
+    ("java", "Java", "text/x-java"),
+    ("css", "CSS", "text/css"),
+    ("sql", "SQL", "text/x-sql"),
+    ("diff", "Diff", "text/x-diff"),
+];
+
+/// Picks the mime type of a paste from its language, or the extension of its name.
+pub fn paste_mime_type(name: &str, language: Option<&str>) -> &'static str {
+    let extension = language.or_else(|| name.rsplit_once('.').map(|(_, ext)| ext));
+    PASTE_LANGUAGES
+        .iter()
+        .find(|(ext, _, _)| Some(*ext) == extension)
+        .map(|(_, _, mime)| *mime)
+        .unwrap_or("text/plain")
+}
+
+/// Whether a file with this mime type can be shown as a paste
+pub fn is_text_mime(mime_type: &str) -> bool {
+    mime_type.starts_with("text/") || PASTE_LANGUAGES.iter().any(|(_, _, x)| *x == mime_type)
+}
+
+/// Given the uid, get the link of a files thumbnail.
+pub fn get_thumbnail_link(uid: String) -> String {
+    format!("/api/file/{}/thumb", uid)
+}
+
+/// Absolute link to the contents of a file. Points to the user content domain if there is one,
+/// except for password protected files, since their password cookie only works on this domain.
+pub fn content_link(info: &FileInfo, config: &AppConfig, headers: &HeaderMap) -> String {
+    let base = match &config.user_content_url {
+        Some(x) if info.password_hash.is_none() => x.trim_end_matches('/').to_string(),
+        _ => base_url(config, headers),
+    };
+    format!("{}{}", base, get_download_link(info.id.clone()))
+}
+
+/// Whether a request came in through the user content domain
+pub fn is_user_content_request(config: &AppConfig, headers: &HeaderMap) -> bool {
+    let Some(user_content_url) = &config.user_content_url else {
+        return false;
+    };
+    let user_content_host = user_content_url
+        .split_once("://")
+        .map_or(user_content_url.as_str(), |(_, x)| x)
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    headers
+        .get(header::HOST)
+        .and_then(|x| x.to_str().ok())
+        .is_some_and(|x| x.eq_ignore_ascii_case(user_content_host))
+}
+
+/// The url filebin is reachable at, without a trailing slash.
+pub fn base_url(config: &AppConfig, headers: &HeaderMap) -> String {
+    match &config.public_url {
+        Some(x) => x.trim_end_matches('/').to_string(),
+        None => format!(
+            "http://{}",
+            headers
+                .get(header::HOST)
+                .and_then(|x| x.to_str().ok())
+                .unwrap_or("localhost")
+        ),
+    }
+}
+
+/// Formats a byte count like "1.5 MiB"
+pub fn readable_size(bytes: u128) -> String {
+    byte_unit::Byte::from_bytes(bytes)
+        .get_appropriate_unit(true)
+        .to_string()
+        .replace(".00", "")
+}
+
+pub fn should_preview(info: &FileInfo, config: &AppConfig) -> bool {
+    let display_filter = Regex::new(&config.allowed_preview_mime_regex).unwrap();
+    info.preview && display_filter.is_match(&info.detected_mime_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    state.db.apply_batch(batch)?;
-
-    if (used_credits + credit_cost) as u128 > state.config.ratelimit_period_byte_limit.get_bytes() {
-        return Ok(false);
-    } else {
-        if !dry {
-            state.db.insert(
-                format!(
-                    "{}:{}",
-                    prefix,
-                    SystemTime::now()
-                        .duration_since(SystemTime::UNIX_EPOCH)?
-                        .as_secs()
-                ),
-                credit_cost.to_le_bytes().to_vec(),
-            )?;
-        }
-        return Ok(true);
+    #[test]
+    fn tokens_have_to_match_exactly() {
+        let tokens = vec!["first".to_string(), "second".to_string()];
+        assert!(is_one_of("first", &tokens));
+        assert!(is_one_of("second", &tokens));
+        assert!(!is_one_of("secon", &tokens));
+        assert!(!is_one_of("second2", &tokens));
+        assert!(!is_one_of("", &tokens));
+        assert!(!is_one_of("first", &[]));
     }
 }